@@ -0,0 +1,354 @@
+use crate::config::is_inside_app_dir;
+use crate::logging::timestamp;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const DEFAULT_KEEP: u32 = 5;
+/// Lines of stderr kept in memory for the exit diagnostics.
+const STDERR_TAIL_LINES: usize = 10;
+
+config_struct! {
+    /// Where and how to capture the app's stdout and stderr.
+    pub struct AppLogConfig {
+        /// Log file, relative to the app dir. Defaults to `logs/app.log`. As
+        /// with the adapter's own log, capture is off unless the directory
+        /// already exists.
+        pub path: Option<PathBuf>,
+        /// Size in bytes at which the log is rotated. Defaults to 10 MiB.
+        pub max_bytes: Option<u64>,
+        /// Number of rotated files (`app.log.1`, `app.log.2`, ...) to keep.
+        /// Defaults to 5.
+        pub keep: Option<u32>,
+    }
+}
+
+impl AppLogConfig {
+    /// Checks that the log stays inside the app dir.
+    pub fn validate(&self) -> Result<(), String> {
+        match &self.path {
+            Some(path) if !is_inside_app_dir(path) => Err(format!(
+                "`{}` must be a relative path inside the app dir",
+                path.display()
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// An append-only log file that rotates itself once it grows past
+/// `max_bytes`, keeping `keep` older generations.
+pub struct RotatingLog {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: u32,
+    /// Whether the file must be a private one of ours, see `open_private`.
+    private: bool,
+    file: File,
+    size: u64,
+}
+
+impl RotatingLog {
+    pub fn open(path: PathBuf, max_bytes: u64, keep: u32) -> io::Result<RotatingLog> {
+        RotatingLog::open_with(path, max_bytes, keep, false)
+    }
+
+    /// Like `open`, for logs other users must not read or plant: the file is
+    /// created with mode 0600, never through a symlink, and refused unless
+    /// it is a regular file we own.
+    pub fn open_private(path: PathBuf, max_bytes: u64, keep: u32) -> io::Result<RotatingLog> {
+        RotatingLog::open_with(path, max_bytes, keep, true)
+    }
+
+    fn open_with(
+        path: PathBuf,
+        max_bytes: u64,
+        keep: u32,
+        private: bool,
+    ) -> io::Result<RotatingLog> {
+        let file = open_file(&path, private)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingLog {
+            path,
+            max_bytes,
+            keep,
+            private,
+            file,
+            size,
+        })
+    }
+
+    /// Opens the app log described by `config`, relative to `app_dir`.
+    pub fn for_app(config: Option<&AppLogConfig>, app_dir: &Path) -> io::Result<RotatingLog> {
+        if let Some(Err(e)) = config.map(AppLogConfig::validate) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
+        }
+        let path = config
+            .and_then(|c| c.path.clone())
+            .unwrap_or_else(|| PathBuf::from("logs/app.log"));
+        RotatingLog::open(
+            app_dir.join(path),
+            config
+                .and_then(|c| c.max_bytes)
+                .unwrap_or(DEFAULT_MAX_BYTES),
+            config.and_then(|c| c.keep).unwrap_or(DEFAULT_KEEP),
+        )
+    }
+
+    /// Writes `line` (without its newline) prefixed with a timestamp and the
+    /// stream it came from.
+    pub fn write_line(&mut self, stream: &str, line: &[u8]) -> io::Result<()> {
+        let mut record = format!("{} {} ", timestamp(), stream).into_bytes();
+        record.extend_from_slice(line);
+        record.push(b'\n');
+        self.append(&record)
+    }
+
+    /// Writes `record` as is, in a single write, rotating first if it would
+    /// take the file past `max_bytes`.
+    pub fn append(&mut self, record: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.size + record.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(record)?;
+        self.size += record.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let generation = |n: u32| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(generation(self.keep));
+            for n in (1..self.keep).rev() {
+                let _ = fs::rename(generation(n), generation(n + 1));
+            }
+            fs::rename(&self.path, generation(1))?;
+        }
+        self.file = open_file(&self.path, self.private)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn open_file(path: &Path, private: bool) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    if !private {
+        return options.open(path);
+    }
+    let file = options
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)?;
+    // Checked on the open file, so it can't be swapped after the check.
+    let metadata = file.metadata()?;
+    // SAFETY: getuid has no preconditions and can't fail.
+    let uid = unsafe { libc::getuid() };
+    if !metadata.is_file() || metadata.uid() != uid {
+        let message = format!("{} is not a file owned by uid {}", path.display(), uid);
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, message));
+    }
+    Ok(file)
+}
+
+/// Background threads copying the child's stdout and stderr through to ours
+/// while recording every line in a shared `RotatingLog`, if there is one, and
+/// remembering the last lines of stderr. A restarted app gets a new `Capture`
+/// on the same log.
+pub struct Capture {
+    done: mpsc::Receiver<()>,
+    streams: usize,
+    stderr_tail: Arc<Mutex<VecDeque<String>>>,
+}
+
+impl Capture {
+    pub fn start(
+        log: Option<Arc<Mutex<RotatingLog>>>,
+        stdout: Option<impl Read + Send + 'static>,
+        stderr: Option<impl Read + Send + 'static>,
+    ) -> Capture {
+        let (tx, done) = mpsc::channel();
+        let stderr_tail = Arc::new(Mutex::new(VecDeque::new()));
+        let mut streams = 0;
+        if let Some(stdout) = stdout {
+            tee(
+                stdout,
+                io::stdout(),
+                "stdout",
+                log.clone(),
+                None,
+                tx.clone(),
+            );
+            streams += 1;
+        }
+        if let Some(stderr) = stderr {
+            tee(
+                stderr,
+                io::stderr(),
+                "stderr",
+                log,
+                Some(stderr_tail.clone()),
+                tx,
+            );
+            streams += 1;
+        }
+        Capture {
+            done,
+            streams,
+            stderr_tail,
+        }
+    }
+
+    /// Waits up to `timeout` for both streams to reach EOF, so the last lines
+    /// the app printed make it into the log before we exit. Returns the last
+    /// lines of stderr.
+    pub fn finish(self, timeout: Duration) -> Vec<String> {
+        let deadline = Instant::now() + timeout;
+        for _ in 0..self.streams {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if self.done.recv_timeout(remaining).is_err() {
+                debug_log!("gave up waiting for app output to drain");
+                break;
+            }
+        }
+        self.stderr_tail
+            .lock()
+            .map(|tail| tail.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+fn tee(
+    source: impl Read + Send + 'static,
+    mut sink: impl Write + Send + 'static,
+    stream: &'static str,
+    log: Option<Arc<Mutex<RotatingLog>>>,
+    tail: Option<Arc<Mutex<VecDeque<String>>>>,
+    done: mpsc::Sender<()>,
+) {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(source);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) => break,
+                Ok(_) => {
+                    let _ = sink.write_all(&line);
+                    let _ = sink.flush();
+                    let content = line.strip_suffix(b"\n").unwrap_or(&line);
+                    if let Some(Ok(mut log)) = log.as_ref().map(|log| log.lock()) {
+                        if let Err(e) = log.write_line(stream, content) {
+                            debug_log!("failed to write app log: {}", e);
+                        }
+                    }
+                    if let Some(Ok(mut tail)) = tail.as_ref().map(|tail| tail.lock()) {
+                        if tail.len() == STDERR_TAIL_LINES {
+                            tail.pop_front();
+                        }
+                        tail.push_back(String::from_utf8_lossy(content).into_owned());
+                    }
+                }
+                Err(e) => {
+                    debug_log!("failed to read app {}: {}", stream, e);
+                    break;
+                }
+            }
+        }
+        let _ = done.send(());
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_rotation_and_retention() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("app.log");
+        let mut log = RotatingLog::open(path.clone(), 100, 2).unwrap();
+
+        // Each record is a ~25 byte timestamp plus the stream name and line.
+        for i in 0..12 {
+            log.write_line(
+                "stdout",
+                format!("line {:02} {}", i, "x".repeat(40)).as_bytes(),
+            )
+            .unwrap();
+        }
+
+        let current = fs::read_to_string(&path).unwrap();
+        assert!(current.ends_with(&format!("stdout line 11 {}\n", "x".repeat(40))));
+        assert!(fs::metadata(&path).unwrap().len() <= 100);
+        assert!(temp_dir.path().join("app.log.1").exists());
+        assert!(temp_dir.path().join("app.log.2").exists());
+        assert!(!temp_dir.path().join("app.log.3").exists());
+    }
+
+    #[test]
+    fn test_log_stays_in_app_dir() {
+        let temp_dir = tempdir().unwrap();
+        let app_dir = temp_dir.path().join("app");
+        fs::create_dir_all(app_dir.join("logs")).unwrap();
+        for path in ["/tmp/app.log", "../app.log", "logs/../../app.log", ""] {
+            let config = AppLogConfig {
+                path: Some(PathBuf::from(path)),
+                max_bytes: None,
+                keep: None,
+            };
+            assert!(config.validate().is_err(), "{:?}", path);
+            let err = RotatingLog::for_app(Some(&config), &app_dir).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+        assert!(!temp_dir.path().join("app.log").exists());
+        assert!(RotatingLog::for_app(None, &app_dir).is_ok());
+    }
+
+    #[test]
+    fn test_stderr_tail_without_log() {
+        let stderr: String = (1..=15).map(|i| format!("line {}\n", i)).collect();
+        let capture = Capture::start(None, None::<&[u8]>, Some(std::io::Cursor::new(stderr)));
+        let stderr_tail = capture.finish(Duration::from_secs(5));
+        assert_eq!(stderr_tail.len(), STDERR_TAIL_LINES);
+        assert_eq!(stderr_tail.first().map(String::as_str), Some("line 6"));
+        assert_eq!(stderr_tail.last().map(String::as_str), Some("line 15"));
+    }
+
+    #[test]
+    fn test_capture_records_both_streams() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("app.log");
+        let log = RotatingLog::open(path.clone(), DEFAULT_MAX_BYTES, DEFAULT_KEEP).unwrap();
+
+        let capture = Capture::start(
+            Some(Arc::new(Mutex::new(log))),
+            Some(&b"hello\nworld"[..]),
+            Some(&b"+ python3 -m http.server\n"[..]),
+        );
+        let stderr_tail = capture.finish(Duration::from_secs(5));
+        assert_eq!(stderr_tail, ["+ python3 -m http.server"]);
+
+        let content = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().any(|l| l.ends_with(" stdout hello")));
+        assert!(lines.iter().any(|l| l.ends_with(" stdout world")));
+        assert!(lines
+            .iter()
+            .any(|l| l.ends_with(" stderr + python3 -m http.server")));
+        assert!(lines
+            .iter()
+            .all(|l| l.as_bytes()[4] == b'-' && l.contains('T')));
+    }
+}
@@ -0,0 +1,331 @@
+use crate::applog::RotatingLog;
+use crate::config::is_inside_app_dir;
+use crate::logging::{self, Event, Level};
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io::{self, BufRead, BufReader, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Instant;
+
+/// Written to the output dir after a successful build, holding the hash of
+/// what it was built from.
+const STAMP_FILE: &str = ".smallweb-build";
+/// Build output is captured here when the app's `logs/` dir exists.
+const LOG_FILE: &str = "logs/build.log";
+const LOG_MAX_BYTES: u64 = 1024 * 1024;
+/// Lines of build output shown when the build fails.
+const TAIL_LINES: usize = 20;
+
+config_struct! {
+    /// A command producing the app's runnable form before it starts.
+    pub struct BuildConfig {
+        /// Shell command run from the app dir in the app's sandbox, with
+        /// write access to `output` only.
+        pub command: String,
+        /// Files and directories, relative to the app dir, the build reads.
+        /// Directories are walked recursively, skipping hidden entries and
+        /// `output`. The build is skipped while their contents and `command`
+        /// are unchanged since the last successful one.
+        pub inputs: Vec<PathBuf>,
+        /// Directory the build writes to, relative to the app dir. Created if
+        /// missing; the build may empty it but not remove it.
+        pub output: PathBuf,
+    }
+}
+
+impl BuildConfig {
+    /// Checks that all paths stay inside the app dir.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(path) = self
+            .inputs
+            .iter()
+            .chain([&self.output])
+            .find(|path| !is_inside_app_dir(path))
+        {
+            return Err(format!(
+                "`{}` must be a relative path inside the app dir",
+                path.display()
+            ));
+        }
+        if self.output.components().all(|c| c == Component::CurDir) {
+            return Err("`output` must be a directory below the app dir".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// 64-bit FNV-1a: stable across releases and platforms, unlike std's
+/// `DefaultHasher`, and fast enough for source trees.
+struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Fnv {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Hash of everything the build depends on: its command, its output dir
+/// and the names and contents of its input files.
+fn input_hash(config: &BuildConfig, app_dir: &Path) -> io::Result<String> {
+    let mut hasher = Fnv::default();
+    hasher.write(config.command.as_bytes());
+    hasher.write(&[0]);
+    hasher.write(config.output.as_os_str().as_bytes());
+    hasher.write(&[0]);
+    let output = app_dir.join(&config.output);
+    let mut files = Vec::new();
+    for input in &config.inputs {
+        let path = app_dir.join(input);
+        if path.exists() {
+            collect_files(&path, &output, &mut files)?;
+        } else {
+            // Creating a declared input later must trigger a build too.
+            hasher.write(input.as_os_str().as_bytes());
+            hasher.write(b"\0missing\0");
+        }
+    }
+    files.sort();
+    files.dedup();
+    let mut buffer = vec![0; 64 * 1024];
+    for path in files {
+        let relative = path.strip_prefix(app_dir).unwrap_or(&path);
+        hasher.write(relative.as_os_str().as_bytes());
+        hasher.write(&[0]);
+        let mut file = File::open(&path)?;
+        hasher.write_u64(file.metadata()?.len());
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.write(&buffer[..read]);
+        }
+    }
+    Ok(format!("{:016x}", hasher.finish()))
+}
+
+/// Adds the files at or below `path` to `files`. Symlinked directories are
+/// not followed.
+fn collect_files(path: &Path, output: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    if path == output {
+        return Ok(());
+    }
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        if path.is_file() {
+            files.push(path.to_path_buf());
+        }
+        return Ok(());
+    }
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if !entry.file_name().as_bytes().starts_with(b".") {
+            collect_files(&entry.path(), output, files)?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs the build for the app in `app_dir` inside a sandbox made of
+/// `bwrap_args`, unless the last successful one had the same inputs. Output
+/// goes to the build log only; on failure the error includes its last lines.
+pub fn run(config: &BuildConfig, app_dir: &Path, bwrap_args: &[String]) -> Result<(), String> {
+    let started = Instant::now();
+    let output = app_dir.join(&config.output);
+    fs::create_dir_all(&output)
+        .map_err(|e| format!("failed to create {}: {}", output.display(), e))?;
+    // The path is checked above, but a symlink on the way would still let
+    // the build write wherever it points.
+    let canonical =
+        |path: &Path| fs::canonicalize(path).map_err(|e| format!("{}: {}", path.display(), e));
+    let (output, real_app_dir) = (canonical(&output)?, canonical(app_dir)?);
+    let below = match output.strip_prefix(&real_app_dir) {
+        Ok(below) if !below.as_os_str().is_empty() => below.to_path_buf(),
+        _ => {
+            return Err(format!(
+                "{} is not a directory inside {}",
+                output.display(),
+                real_app_dir.display()
+            ))
+        }
+    };
+    let hash =
+        input_hash(config, app_dir).map_err(|e| format!("failed to read build inputs: {}", e))?;
+    let stamp = output.join(STAMP_FILE);
+    if fs::read_to_string(&stamp).is_ok_and(|last| last.trim() == hash) {
+        logging::event(
+            Level::Info,
+            Event::Build {
+                cached: true,
+                duration_ms: 0,
+            },
+        );
+        return Ok(());
+    }
+    // A build that fails halfway leaves nothing to reuse.
+    let _ = fs::remove_file(&stamp);
+
+    let mut log = match RotatingLog::open(app_dir.join(LOG_FILE), LOG_MAX_BYTES, 1) {
+        Ok(log) => Some(log),
+        Err(e) => {
+            debug_log!("not capturing build output to {}: {}", LOG_FILE, e);
+            None
+        }
+    };
+    let (reader, writer) = io::pipe().map_err(|e| format!("failed to create pipe: {}", e))?;
+    let stderr = writer
+        .try_clone()
+        .map_err(|e| format!("failed to create pipe: {}", e))?;
+    // Mounted where the app dir, as the sandbox sees it, has it.
+    let output_dir = output.display().to_string();
+    let sandbox_dir = app_dir.join(below).display().to_string();
+    let mut command = Command::new("bwrap");
+    command
+        .args(bwrap_args)
+        .args([
+            "--bind",
+            &output_dir,
+            &sandbox_dir,
+            "--",
+            "/bin/sh",
+            "-c",
+            &config.command,
+        ])
+        .current_dir(app_dir)
+        .stdin(Stdio::null())
+        .stdout(writer)
+        .stderr(stderr);
+    info_log!("building: {}", config.command);
+    let mut child = command
+        .spawn()
+        .map_err(|e| format!("failed to spawn bwrap: {}", e))?;
+    // Our copies of the write end must go, or the reader never sees EOF.
+    drop(command);
+
+    let mut tail = VecDeque::with_capacity(TAIL_LINES);
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    while reader.read_until(b'\n', &mut line).unwrap_or(0) > 0 {
+        let content = line.strip_suffix(b"\n").unwrap_or(&line);
+        if let Some(log) = log.as_mut() {
+            if let Err(e) = log.write_line("build", content) {
+                debug_log!("failed to write build log: {}", e);
+            }
+        }
+        if tail.len() == TAIL_LINES {
+            tail.pop_front();
+        }
+        tail.push_back(String::from_utf8_lossy(content).into_owned());
+        line.clear();
+    }
+    let status = child
+        .wait()
+        .map_err(|e| format!("failed to wait for build: {}", e))?;
+    if !status.success() {
+        let lines: Vec<String> = tail.into_iter().map(|line| format!("  {}", line)).collect();
+        return Err(format!("build failed ({}):\n{}", status, lines.join("\n")));
+    }
+    fs::write(&stamp, format!("{}\n", hash))
+        .map_err(|e| format!("failed to write {}: {}", stamp.display(), e))?;
+    let duration_ms = started.elapsed().as_millis() as u64;
+    logging::event(
+        Level::Info,
+        Event::Build {
+            cached: false,
+            duration_ms,
+        },
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn config(command: &str) -> BuildConfig {
+        BuildConfig {
+            command: command.to_string(),
+            inputs: vec![PathBuf::from("src"), PathBuf::from("go.mod")],
+            output: PathBuf::from("build"),
+        }
+    }
+
+    #[test]
+    fn test_input_hash() {
+        let app_dir = tempdir().unwrap();
+        let app_dir = app_dir.path();
+        fs::create_dir_all(app_dir.join("src/.cache")).unwrap();
+        fs::write(app_dir.join("src/main.go"), "package main").unwrap();
+        let config = config("go build -o build/app ./src");
+        let first = input_hash(&config, app_dir).unwrap();
+
+        fs::write(app_dir.join("src/.cache/x"), "ignored").unwrap();
+        fs::create_dir(app_dir.join("build")).unwrap();
+        fs::write(app_dir.join("build/app"), "binary").unwrap();
+        assert_eq!(input_hash(&config, app_dir).unwrap(), first);
+
+        fs::write(app_dir.join("go.mod"), "module app").unwrap();
+        let second = input_hash(&config, app_dir).unwrap();
+        assert_ne!(second, first);
+        fs::write(app_dir.join("src/main.go"), "package main\n").unwrap();
+        assert_ne!(input_hash(&config, app_dir).unwrap(), second);
+        assert_ne!(
+            input_hash(&self::config("go build"), app_dir).unwrap(),
+            second
+        );
+
+        let mut hasher = Fnv::default();
+        hasher.write(b"a");
+        assert_eq!(hasher.finish(), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(config("make").validate().is_ok());
+        let mut outside = config("make");
+        outside.inputs.push(PathBuf::from("../shared"));
+        assert_eq!(
+            outside.validate().unwrap_err(),
+            "`../shared` must be a relative path inside the app dir"
+        );
+        let mut outside = config("make");
+        outside.output = PathBuf::from("/srv/build");
+        assert!(outside.validate().is_err());
+        outside.output = PathBuf::from(".");
+        assert!(outside.validate().is_err());
+    }
+
+    #[test]
+    fn test_symlinked_output_refused() {
+        let temp_dir = tempdir().unwrap();
+        let app_dir = temp_dir.path().join("app");
+        let outside = temp_dir.path().join("outside");
+        fs::create_dir_all(&app_dir).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, app_dir.join("build")).unwrap();
+
+        let err = run(&config("true"), &app_dir, &[]).unwrap_err();
+        assert!(err.ends_with(&format!(
+            " is not a directory inside {}",
+            fs::canonicalize(&app_dir).unwrap().display()
+        )));
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,877 @@
+use crate::applog::AppLogConfig;
+use crate::build::BuildConfig;
+use crate::fileserver::StaticSite;
+use crate::jsonc;
+use crate::proxy::Upstream;
+use crate::runtime::Runtime;
+use crate::schema::JsonSchema;
+use crate::sidecar::{self, SidecarConfig};
+use crate::supervisor::{Readiness, RestartPolicy};
+use serde_json::{json, Map, Value};
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+
+/// Keys Smallweb itself reads from smallweb.json. They share the file with our
+/// adapter settings and are ignored here.
+const SMALLWEB_KEYS: &[&str] = &[
+    "$schema",
+    "entrypoint",
+    "root",
+    "private",
+    "privateRoutes",
+    "publicRoutes",
+    "crons",
+    "additionalDomains",
+    "admin",
+    "authorizedEmails",
+    "authorizedGroups",
+];
+
+/// Adapter keys accepted at the top level for configs written before the
+/// `adapter` section existed.
+const LEGACY_KEYS: &[&str] = &["exec"];
+
+const ADAPTER_SECTION: &str = "adapter";
+
+config_struct! {
+    /// Settings for launching a non-Deno app, read from the `adapter` section
+    /// of smallweb.json.
+    pub struct SmallwebConfig {
+        /// Shell command that starts the app inside the sandbox. It must
+        /// listen on `$PORT`. One of this, `runtime`, `proxy` or `static` is
+        /// required.
+        pub exec: Option<String>,
+        /// Language preset providing the exec command and the mounts the
+        /// runtime needs: `python`, `node`, `ruby`, `go-binary` or `static`.
+        /// An explicit `exec` overrides the preset's command. `static` serves
+        /// `public/`, or the app dir, like the `static` key.
+        pub runtime: Option<Runtime>,
+        /// Forward `$PORT` to a service running elsewhere instead of starting
+        /// one: `http://host:port`, `tcp://host:port` or `unix:/path`. The
+        /// upstream is reached from outside the sandbox, so any host or
+        /// socket the adapter's user can connect to may be named.
+        pub proxy: Option<String>,
+        /// Directory inside the app dir that the adapter serves over HTTP on
+        /// `$PORT` itself, with no sandboxed process: `"public/"`, or
+        /// `{"dir": "dist", "spa": true}` to answer unknown routes with
+        /// `index.html`.
+        pub r#static: Option<StaticSite>,
+        /// Command building the app before it starts, skipped while its
+        /// inputs are unchanged: `{"command": "go build -o build/app .",
+        /// "inputs": ["go.mod", "go.sum", "cmd"], "output": "build"}`.
+        pub build: Option<BuildConfig>,
+        /// Processes started in the app's sandbox before `exec`, in order,
+        /// such as a queue worker or a local Redis: `[{"name": "redis",
+        /// "exec": "redis-server --port 6379", "ready_port": 6379}]`. They
+        /// are stopped with the app, which is stopped when a critical one
+        /// exits.
+        pub sidecars: Option<Vec<SidecarConfig>>,
+        /// Absolute path inside the sandbox of a Unix socket the app listens
+        /// on instead of `$PORT`, also passed to it as `$SOCKET`. Its
+        /// directory is created and mounted by the adapter, which bridges
+        /// `$PORT` to the socket.
+        pub socket: Option<PathBuf>,
+        /// Bind `$PORT` in the adapter and pass the listening socket to the
+        /// app as fd 3, following systemd's `LISTEN_FDS` protocol. READY is
+        /// printed straight away and the app needs no network access.
+        pub socket_activation: Option<bool>,
+        /// Fixed port the app listens on when it can't be told to use
+        /// `$PORT`. The sandbox then gets a network namespace of its own, with
+        /// no outside network, and the adapter forwards `$PORT` into it.
+        pub listen_port: Option<u16>,
+        /// Seconds without connections after which the app is stopped and
+        /// the adapter exits cleanly, to be started again on the next
+        /// request. Only when the adapter fronts the port: with `proxy`,
+        /// `static`, `socket`, `socket_activation` or `listen_port`.
+        pub idle_timeout: Option<u64>,
+        /// How to tell the app is up: `port` (the default) waits for `$PORT`,
+        /// or the `socket`, to accept connections; `notify` waits for
+        /// `READY=1` on `$NOTIFY_SOCKET`, logging `STATUS=` messages.
+        pub ready: Option<Readiness>,
+        /// Seconds the app gets to exit after Smallweb stops it (SIGTERM,
+        /// SIGINT or SIGHUP are forwarded) before it is killed. Defaults to 10.
+        pub shutdown_timeout: Option<u64>,
+        /// Capture of the app's stdout and stderr into a rotating log file.
+        pub log: Option<AppLogConfig>,
+        /// Whether and how often to restart the app when it exits after
+        /// READY.
+        pub restart: Option<RestartPolicy>,
+    }
+}
+
+impl SmallwebConfig {
+    /// Whether the adapter serves the app's files itself rather than
+    /// starting it: `static` is set, or the `static` runtime has no `exec`.
+    pub fn serves_static(&self) -> bool {
+        self.r#static.is_some() || (self.runtime == Some(Runtime::Static) && self.exec.is_none())
+    }
+}
+
+/// Config file names checked in each directory, in order of preference.
+pub const CONFIG_FILE_NAMES: &[&str] = &["smallweb.json", "smallweb.jsonc"];
+
+/// A problem found in a config file, with the 1-based line and column of the
+/// offending key or syntax error when known.
+#[derive(Debug, PartialEq)]
+pub struct ConfigError {
+    pub message: String,
+    pub position: Option<(usize, usize)>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.position {
+            Some((line, column)) => {
+                write!(f, "line {}, column {}: {}", line, column, self.message)
+            }
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl ConfigError {
+    fn new(message: impl Into<String>) -> Self {
+        ConfigError {
+            message: message.into(),
+            position: None,
+        }
+    }
+
+    /// Points the error at the first `"key":` in `json`.
+    fn at_key(json: &str, key: &str, message: impl Into<String>) -> Self {
+        ConfigError {
+            message: message.into(),
+            position: jsonc::key_position(json, key),
+        }
+    }
+}
+
+/// Parses the adapter settings out of a smallweb.json(c) document. Comments
+/// and trailing commas are accepted. All problems found are reported
+/// together, each with its line/column position where known.
+///
+/// Returns `Ok(None)` when the file holds only Smallweb's own keys, i.e. the
+/// app is a regular Deno app that happens to have a smallweb.json.
+pub fn parse_config(content: &str) -> Result<Option<SmallwebConfig>, Vec<ConfigError>> {
+    let json = jsonc::strip(content).map_err(|e| {
+        vec![ConfigError {
+            message: e.message,
+            position: Some((e.line, e.column)),
+        }]
+    })?;
+    let root: Map<String, Value> = match serde_json::from_str(&json) {
+        Ok(Value::Object(map)) => map,
+        Ok(_) => return Err(vec![ConfigError::new("expected a JSON object")]),
+        Err(e) => {
+            let message = e.to_string();
+            let suffix = format!(" at line {} column {}", e.line(), e.column());
+            return Err(vec![ConfigError {
+                message: message
+                    .strip_suffix(&suffix)
+                    .unwrap_or(&message)
+                    .to_string(),
+                position: Some((e.line(), e.column())),
+            }]);
+        }
+    };
+
+    let mut errors = Vec::new();
+    let mut settings = Map::new();
+    let mut is_adapter_config = false;
+
+    for (key, value) in &root {
+        if key == ADAPTER_SECTION {
+            is_adapter_config = true;
+            let Some(section) = value.as_object() else {
+                let message = format!("`{}` must be an object", key);
+                errors.push(ConfigError::at_key(&json, key, message));
+                continue;
+            };
+            for (key, value) in section {
+                if SmallwebConfig::FIELDS.contains(&key.as_str()) {
+                    // null means unset, for the checks below as for serde.
+                    if !value.is_null() {
+                        settings.insert(key.clone(), value.clone());
+                    }
+                } else {
+                    let message = unknown_key_message(
+                        &format!("{}.{}", ADAPTER_SECTION, key),
+                        key,
+                        SmallwebConfig::FIELDS,
+                    );
+                    errors.push(ConfigError::at_key(&json, key, message));
+                }
+            }
+        } else if LEGACY_KEYS.contains(&key.as_str()) {
+            is_adapter_config = true;
+        } else if SMALLWEB_KEYS.contains(&key.as_str()) {
+            debug_log!("ignoring Smallweb key `{}`", key);
+        } else if suggest(key, &[ADAPTER_SECTION]).is_some() {
+            // A misspelled section would silently launch the app with Deno.
+            let message = unknown_key_message(key, key, &[ADAPTER_SECTION]);
+            errors.push(ConfigError::at_key(&json, key, message));
+        } else if let Some(suggestion) = suggest(key, &top_level_candidates()) {
+            // The rest of the top level belongs to Smallweb, which may add
+            // keys we don't know yet.
+            warn_log!("unknown key `{}`, did you mean `{}`?", key, suggestion);
+        } else {
+            debug_log!("ignoring unknown key `{}`", key);
+        }
+    }
+
+    for key in LEGACY_KEYS {
+        if let Some(value) = root.get(*key).filter(|value| !value.is_null()) {
+            if settings.contains_key(*key) {
+                let message = format!(
+                    "`{0}` is set both at the top level and in `{1}.{0}`",
+                    key, ADAPTER_SECTION
+                );
+                errors.push(ConfigError::at_key(&json, key, message));
+            }
+            settings.insert(key.to_string(), value.clone());
+        }
+    }
+
+    if !is_adapter_config && errors.is_empty() {
+        return Ok(None);
+    }
+
+    for (key, message) in SmallwebConfig::field_errors(&settings) {
+        errors.push(if settings.contains_key(key) {
+            ConfigError::at_key(&json, key, format!("`{}`: {}", key, message))
+        } else {
+            ConfigError::new(message)
+        });
+    }
+    let launcher = ["exec", "runtime"]
+        .into_iter()
+        .find(|key| settings.contains_key(*key));
+    // The static runtime without an `exec` of its own is the built-in file
+    // server, just like `static`.
+    let static_runtime =
+        settings.get("runtime") == Some(&json!("static")) && !settings.contains_key("exec");
+    let sandbox_keys = [
+        "socket",
+        "socket_activation",
+        "listen_port",
+        "ready",
+        "sidecars",
+    ];
+    if settings.contains_key("static") {
+        let conflict = ["exec", "runtime", "proxy"]
+            .into_iter()
+            .chain(sandbox_keys)
+            .find(|key| settings.contains_key(*key));
+        if let Some(key) = conflict {
+            let message = format!("`static` can't be combined with `{}`", key);
+            errors.push(ConfigError::at_key(&json, "static", message));
+        }
+    } else if static_runtime {
+        if let Some(key) = sandbox_keys
+            .into_iter()
+            .find(|key| settings.contains_key(*key))
+        {
+            let message = format!(
+                "`runtime` \"static\" without `exec` can't be combined with `{}`",
+                key
+            );
+            errors.push(ConfigError::at_key(&json, "runtime", message));
+        }
+    }
+    match (launcher, settings.get("proxy")) {
+        (None, None) if settings.contains_key("static") => {}
+        (None, None) => errors.push(ConfigError::new(
+            "one of `exec`, `runtime`, `proxy` or `static` is required",
+        )),
+        (Some(key), Some(_)) => errors.push(ConfigError::at_key(
+            &json,
+            "proxy",
+            format!("`{}` and `proxy` can't both be set", key),
+        )),
+        (None, Some(Value::String(proxy))) => {
+            if let Err(e) = Upstream::parse(proxy) {
+                errors.push(ConfigError::at_key(
+                    &json,
+                    "proxy",
+                    format!("`proxy`: {}", e),
+                ));
+            }
+        }
+        _ => {}
+    }
+    if let Some(socket) = settings.get("socket").and_then(Value::as_str) {
+        let path = std::path::Path::new(socket);
+        if !path.is_absolute() || path.file_name().is_none() {
+            let message = format!("`socket`: `{}` must be an absolute path to a file", socket);
+            errors.push(ConfigError::at_key(&json, "socket", message));
+        } else if settings.contains_key("proxy") {
+            errors.push(ConfigError::at_key(
+                &json,
+                "socket",
+                "`socket` needs `exec`, not `proxy`",
+            ));
+        }
+    }
+    if let Some(Ok(build)) = settings
+        .get("build")
+        .map(|b| serde_json::from_value::<BuildConfig>(b.clone()))
+    {
+        if let Err(e) = build.validate() {
+            errors.push(ConfigError::at_key(
+                &json,
+                "build",
+                format!("`build`: {}", e),
+            ));
+        }
+        if settings.contains_key("proxy") {
+            errors.push(ConfigError::at_key(
+                &json,
+                "build",
+                "`build` can't be combined with `proxy`",
+            ));
+        }
+    }
+    if let Some(Ok(log)) = settings
+        .get("log")
+        .map(|l| serde_json::from_value::<AppLogConfig>(l.clone()))
+    {
+        if let Err(e) = log.validate() {
+            errors.push(ConfigError::at_key(&json, "log", format!("`log`: {}", e)));
+        }
+    }
+    if let Some(Ok(sidecars)) = settings
+        .get("sidecars")
+        .map(|s| serde_json::from_value::<Vec<SidecarConfig>>(s.clone()))
+    {
+        if let Err(e) = sidecar::validate(&sidecars) {
+            errors.push(ConfigError::at_key(
+                &json,
+                "sidecars",
+                format!("`sidecars`: {}", e),
+            ));
+        }
+        if settings.contains_key("proxy") {
+            errors.push(ConfigError::at_key(
+                &json,
+                "sidecars",
+                "`sidecars` needs `exec`, not `proxy`",
+            ));
+        }
+    }
+    if settings.contains_key("ready") && settings.contains_key("proxy") {
+        errors.push(ConfigError::at_key(
+            &json,
+            "ready",
+            "`ready` needs `exec`, not `proxy`",
+        ));
+    }
+    if settings.get("socket_activation") == Some(&Value::Bool(true)) {
+        let conflict = ["proxy", "socket"]
+            .into_iter()
+            .find(|key| settings.contains_key(*key));
+        if let Some(key) = conflict {
+            let message = format!("`socket_activation` can't be combined with `{}`", key);
+            errors.push(ConfigError::at_key(&json, "socket_activation", message));
+        }
+    }
+    if settings.contains_key("listen_port") {
+        let conflict = ["proxy", "socket", "socket_activation"]
+            .into_iter()
+            .find(|key| settings.contains_key(*key));
+        if let Some(key) = conflict {
+            let message = format!("`listen_port` can't be combined with `{}`", key);
+            errors.push(ConfigError::at_key(&json, "listen_port", message));
+        }
+    }
+    if settings.contains_key("idle_timeout") {
+        let fronted = ["proxy", "static", "socket", "listen_port"]
+            .iter()
+            .any(|key| settings.contains_key(*key))
+            || settings.get("socket_activation") == Some(&Value::Bool(true))
+            || static_runtime;
+        if !fronted {
+            let message = "`idle_timeout` needs `proxy`, `static`, `socket`, `socket_activation` or `listen_port`, \
+                           otherwise connections don't pass through the adapter";
+            errors.push(ConfigError::at_key(&json, "idle_timeout", message));
+        }
+    }
+    if !errors.is_empty() {
+        // Keys are visited in sorted order; report errors in file order.
+        errors.sort_by_key(|e| e.position.unwrap_or((usize::MAX, usize::MAX)));
+        return Err(errors);
+    }
+
+    serde_json::from_value(Value::Object(settings))
+        .map(Some)
+        .map_err(|e| vec![ConfigError::new(e.to_string())])
+}
+
+/// Whether `path` is a non-empty relative path that stays inside its base.
+pub fn is_inside_app_dir(path: &Path) -> bool {
+    !path.as_os_str().is_empty()
+        && path
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+/// JSON Schema for smallweb.json as far as the adapter is concerned: the
+/// `adapter` section in full, plus Smallweb's own keys left unconstrained.
+pub fn schema() -> Value {
+    let mut properties = Map::new();
+    for key in SMALLWEB_KEYS {
+        properties.insert(
+            key.to_string(),
+            json!({ "description": "Read by Smallweb." }),
+        );
+    }
+    let mut adapter = SmallwebConfig::schema();
+    adapter["oneOf"] = json!([
+        { "anyOf": [{ "required": ["exec"] }, { "required": ["runtime"] }] },
+        { "required": ["proxy"] },
+        { "required": ["static"] },
+    ]);
+    properties.insert(ADAPTER_SECTION.to_string(), adapter);
+    properties.insert(
+        "exec".to_string(),
+        json!({
+            "type": "string",
+            "description": "Legacy spelling of `adapter.exec`.",
+            "deprecated": true,
+        }),
+    );
+    json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "smallweb.json (smallweb-adapter)",
+        "type": "object",
+        "properties": properties,
+    })
+}
+
+fn top_level_candidates() -> Vec<&'static str> {
+    let mut candidates = vec![ADAPTER_SECTION];
+    candidates.extend_from_slice(LEGACY_KEYS);
+    candidates
+}
+
+fn unknown_key_message(path: &str, key: &str, candidates: &[&str]) -> String {
+    match suggest(key, candidates) {
+        Some(suggestion) => format!("unknown key `{}`, did you mean `{}`?", path, suggestion),
+        None => format!(
+            "unknown key `{}`, expected one of: {}",
+            path,
+            candidates.join(", ")
+        ),
+    }
+}
+
+/// Returns the candidate closest to `key` if it is plausibly a typo of it.
+fn suggest<'a>(key: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    candidates
+        .iter()
+        .map(|candidate| (edit_distance(key, candidate), *candidate))
+        .filter(|(distance, candidate)| *distance <= 2 && *distance < candidate.len())
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let current = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                prev
+            } else {
+                1 + prev.min(row[j]).min(current)
+            };
+            prev = current;
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_legacy_top_level_exec() {
+        let config = parse_config(r#"{"exec":"./server"}"#).unwrap().unwrap();
+        assert_eq!(config.exec.as_deref(), Some("./server"));
+        assert_eq!(config.shutdown_timeout, None);
+    }
+
+    #[test]
+    fn test_adapter_section_with_smallweb_keys() {
+        let config = parse_config(
+            r#"{"entrypoint":"main.ts","private":true,"crons":[],"adapter":{"exec":"./server"}}"#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(config.exec.as_deref(), Some("./server"));
+    }
+
+    #[test]
+    fn test_smallweb_only_config() {
+        assert_eq!(
+            parse_config(r#"{"entrypoint":"main.ts","root":"public"}"#),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn test_misspelled_keys() {
+        let errors = parse_config(r#"{"adapter":{"exce":"./server"}}"#).unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "line 1, column 13: unknown key `adapter.exce`, did you mean `exec`?"
+        );
+
+        let errors = parse_config(r#"{"adaptor":{"exec":"./server"}}"#).unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "line 1, column 2: unknown key `adaptor`, did you mean `adapter`?"
+        );
+
+        // Possibly a Smallweb key newer than us: only warned about.
+        assert_eq!(parse_config(r#"{"exce":"./server"}"#), Ok(None));
+        let config = parse_config(r#"{"entrypont":"main.ts","adapter":{"exec":"./a"}}"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.exec.as_deref(), Some("./a"));
+    }
+
+    #[test]
+    fn test_jsonc_config() {
+        let config = parse_config(
+            r#"{
+  // Smallweb's own settings
+  "private": true,
+  "adapter": {
+    /* needs its own port */
+    "exec": "./server --port $PORT",
+  },
+}"#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(config.exec.as_deref(), Some("./server --port $PORT"));
+
+        let errors = parse_config("{\n  \"adapter\": {\n    \"exec\": 42\n  }\n}").unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "line 3, column 5: `exec`: invalid type: integer `42`, expected a string"
+        );
+    }
+
+    #[test]
+    fn test_null_is_unset() {
+        for json in [
+            r#"{"adapter":{"exec":null}}"#,
+            r#"{"adapter":{"proxy":null}}"#,
+            r#"{"exec":null,"adapter":{}}"#,
+        ] {
+            let errors = parse_config(json).unwrap_err();
+            assert_eq!(
+                errors[0].to_string(),
+                "one of `exec`, `runtime`, `proxy` or `static` is required",
+                "{}",
+                json
+            );
+        }
+        let config = parse_config(r#"{"exec":null,"adapter":{"exec":"./a","proxy":null}}"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.exec.as_deref(), Some("./a"));
+    }
+
+    #[test]
+    fn test_exec_set_twice() {
+        let errors = parse_config(r#"{"exec":"a","adapter":{"exec":"b"}}"#).unwrap_err();
+        assert!(errors[0].message.contains("both at the top level"));
+    }
+
+    #[test]
+    fn test_proxy() {
+        let config = parse_config(r#"{"adapter":{"proxy":"unix:/run/app.sock"}}"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.proxy.as_deref(), Some("unix:/run/app.sock"));
+        assert_eq!(config.exec, None);
+
+        let errors =
+            parse_config(r#"{"adapter":{"exec":"./server","proxy":"http://a:1"}}"#).unwrap_err();
+        assert_eq!(errors[0].message, "`exec` and `proxy` can't both be set");
+        let errors = parse_config(r#"{"adapter":{"proxy":"ftp://a"}}"#).unwrap_err();
+        assert!(errors[0]
+            .message
+            .starts_with("`proxy`: `ftp://a`: expected"));
+        assert_eq!(errors[0].position, Some((1, 13)));
+    }
+
+    #[test]
+    fn test_socket() {
+        let config = parse_config(r#"{"adapter":{"exec":"gunicorn -b unix:$SOCKET app:app","socket":"/run/app/gunicorn.sock"}}"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.socket, Some(PathBuf::from("/run/app/gunicorn.sock")));
+
+        let errors =
+            parse_config(r#"{"adapter":{"exec":"./server","socket":"app.sock"}}"#).unwrap_err();
+        assert_eq!(
+            errors[0].message,
+            "`socket`: `app.sock` must be an absolute path to a file"
+        );
+    }
+
+    #[test]
+    fn test_socket_activation() {
+        let config =
+            parse_config(r#"{"adapter":{"exec":"exec ./server","socket_activation":true}}"#)
+                .unwrap()
+                .unwrap();
+        assert_eq!(config.socket_activation, Some(true));
+
+        let errors = parse_config(
+            r#"{"adapter":{"exec":"./server","socket":"/run/a.sock","socket_activation":true}}"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            errors[0].message,
+            "`socket_activation` can't be combined with `socket`"
+        );
+        assert!(
+            parse_config(r#"{"adapter":{"exec":"./server","socket_activation":false}}"#).is_ok()
+        );
+    }
+
+    #[test]
+    fn test_listen_port() {
+        let config = parse_config(r#"{"adapter":{"exec":"./legacy-server","listen_port":8080}}"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.listen_port, Some(8080));
+
+        let errors = parse_config(
+            r#"{"adapter":{"exec":"./server","listen_port":8080,"socket_activation":true}}"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            errors[0].message,
+            "`listen_port` can't be combined with `socket_activation`"
+        );
+        let errors =
+            parse_config(r#"{"adapter":{"exec":"./server","listen_port":70000}}"#).unwrap_err();
+        assert!(errors[0].message.starts_with("`listen_port`: "));
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let config = parse_config(
+            r#"{"adapter":{"exec":"exec ./server","socket_activation":true,"idle_timeout":300}}"#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(config.idle_timeout, Some(300));
+        assert!(parse_config(r#"{"adapter":{"proxy":"http://a:1","idle_timeout":60}}"#).is_ok());
+
+        let errors =
+            parse_config(r#"{"adapter":{"exec":"./server","idle_timeout":60}}"#).unwrap_err();
+        assert!(errors[0]
+            .message
+            .starts_with("`idle_timeout` needs `proxy`"));
+    }
+
+    #[test]
+    fn test_static() {
+        let config = parse_config(r#"{"adapter":{"static":"public/","idle_timeout":60}}"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            config.r#static,
+            Some(StaticSite::Dir(PathBuf::from("public/")))
+        );
+        assert!(config.serves_static());
+        let config = parse_config(r#"{"adapter":{"static":{"dir":"dist","spa":true}}}"#)
+            .unwrap()
+            .unwrap();
+        assert!(config.r#static.unwrap().spa());
+        let config = parse_config(r#"{"adapter":{"runtime":"static","idle_timeout":60}}"#)
+            .unwrap()
+            .unwrap();
+        assert!(config.serves_static());
+        let config =
+            parse_config(r#"{"adapter":{"runtime":"static","exec":"exec caddy file-server"}}"#)
+                .unwrap()
+                .unwrap();
+        assert!(!config.serves_static());
+
+        let errors =
+            parse_config(r#"{"adapter":{"static":"public","exec":"./server"}}"#).unwrap_err();
+        assert_eq!(errors[0].message, "`static` can't be combined with `exec`");
+        let errors = parse_config(r#"{"adapter":{"static":{"spa":true}}}"#).unwrap_err();
+        assert!(errors[0].message.starts_with("`static`: "));
+        let errors =
+            parse_config(r#"{"adapter":{"runtime":"static","listen_port":8080}}"#).unwrap_err();
+        assert_eq!(
+            errors[0].message,
+            "`runtime` \"static\" without `exec` can't be combined with `listen_port`"
+        );
+        assert!(parse_config(
+            r#"{"adapter":{"runtime":"static","exec":"caddy","listen_port":8080}}"#
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_build() {
+        let config = parse_config(
+            r#"{"adapter":{"runtime":"go-binary","build":{"command":"go build .","inputs":["go.mod","cmd"],"output":"bin"}}}"#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(config.build.unwrap().output, PathBuf::from("bin"));
+
+        let errors = parse_config(
+            r#"{"adapter":{"exec":"./app","build":{"command":"make","output":"bin"}}}"#,
+        )
+        .unwrap_err();
+        assert_eq!(errors[0].message, "`build`: missing field `inputs`");
+        let errors =
+            parse_config(r#"{"adapter":{"exec":"./app","build":{"command":"make","inputs":[],"output":"/tmp/bin"}}}"#)
+                .unwrap_err();
+        assert_eq!(
+            errors[0].message,
+            "`build`: `/tmp/bin` must be a relative path inside the app dir"
+        );
+    }
+
+    #[test]
+    fn test_log_path_inside_app_dir() {
+        assert!(
+            parse_config(r#"{"adapter":{"exec":"./app","log":{"path":"logs/out.log"}}}"#).is_ok()
+        );
+        let errors =
+            parse_config(r#"{"adapter":{"exec":"./app","log":{"path":"../other/logs/app.log"}}}"#)
+                .unwrap_err();
+        assert_eq!(
+            errors[0].message,
+            "`log`: `../other/logs/app.log` must be a relative path inside the app dir"
+        );
+    }
+
+    #[test]
+    fn test_sidecars() {
+        let config = parse_config(
+            r#"{"adapter":{"exec":"./app","sidecars":[{"name":"redis","exec":"redis-server","ready_port":6379},{"name":"worker","exec":"./worker","critical":false}]}}"#,
+        )
+        .unwrap()
+        .unwrap();
+        let sidecars = config.sidecars.unwrap();
+        assert_eq!(sidecars[0].ready_port, Some(6379));
+        assert_eq!(sidecars[1].critical, Some(false));
+
+        let errors = parse_config(
+            r#"{"adapter":{"proxy":"http://a:1","sidecars":[{"name":"a","exec":"b"}]}}"#,
+        )
+        .unwrap_err();
+        assert_eq!(errors[0].message, "`sidecars` needs `exec`, not `proxy`");
+        let errors = parse_config(r#"{"adapter":{"exec":"./app","sidecars":[{"name":"a","exec":"b"},{"name":"a","exec":"c"}]}}"#)
+            .unwrap_err();
+        assert_eq!(
+            errors[0].message,
+            "`sidecars`: sidecar name `a` is used twice"
+        );
+    }
+
+    #[test]
+    fn test_runtime() {
+        let config = parse_config(r#"{"adapter":{"runtime":"go-binary"}}"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.runtime, Some(Runtime::GoBinary));
+        assert_eq!(config.exec, None);
+        assert!(parse_config(
+            r#"{"adapter":{"runtime":"node","exec":"exec node --inspect index.js"}}"#
+        )
+        .is_ok());
+
+        let errors = parse_config(r#"{"adapter":{"runtime":"php"}}"#).unwrap_err();
+        assert!(errors[0].message.starts_with("`runtime`: "));
+        let errors =
+            parse_config(r#"{"adapter":{"runtime":"python","proxy":"http://a:1"}}"#).unwrap_err();
+        assert_eq!(errors[0].message, "`runtime` and `proxy` can't both be set");
+    }
+
+    #[test]
+    fn test_ready() {
+        let config = parse_config(r#"{"adapter":{"exec":"./server","ready":"notify"}}"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(config.ready, Some(Readiness::Notify));
+
+        let errors =
+            parse_config(r#"{"adapter":{"exec":"./server","ready":"systemd"}}"#).unwrap_err();
+        assert!(errors[0].message.starts_with("`ready`: "));
+        let errors =
+            parse_config(r#"{"adapter":{"proxy":"http://a:1","ready":"port"}}"#).unwrap_err();
+        assert_eq!(errors[0].message, "`ready` needs `exec`, not `proxy`");
+    }
+
+    #[test]
+    fn test_all_errors_reported() {
+        let errors = parse_config(
+            r#"{
+  "adaptr": {},
+  "adapter": {
+    "exce": "./server",
+    "colour": 1
+  }
+}"#,
+        )
+        .unwrap_err();
+        let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            messages,
+            [
+                "line 2, column 3: unknown key `adaptr`, did you mean `adapter`?",
+                "line 4, column 5: unknown key `adapter.exce`, did you mean `exec`?",
+                "line 5, column 5: unknown key `adapter.colour`, expected one of: exec, runtime, proxy, static, \
+                 build, sidecars, socket, socket_activation, listen_port, idle_timeout, ready, shutdown_timeout, \
+                 log, restart",
+                "one of `exec`, `runtime`, `proxy` or `static` is required",
+            ]
+        );
+
+        let errors = parse_config("{\n  \"exec\": \"a\"\n  \"private\": true\n}").unwrap_err();
+        assert_eq!(errors[0].position, Some((3, 3)));
+    }
+
+    #[test]
+    fn test_schema_matches_config() {
+        let schema = schema();
+        let adapter = &schema["properties"]["adapter"];
+        assert_eq!(adapter["additionalProperties"], false);
+        assert_eq!(adapter["required"], json!([]));
+        assert_eq!(adapter["oneOf"][1]["required"], json!(["proxy"]));
+        assert_eq!(
+            adapter["properties"]["exec"]["type"],
+            json!(["string", "null"])
+        );
+        assert_eq!(
+            adapter["properties"]["runtime"]["anyOf"][1],
+            json!({ "type": "null" })
+        );
+        assert!(adapter["properties"]["exec"]["description"]
+            .as_str()
+            .unwrap()
+            .contains("$PORT"));
+        assert_eq!(
+            schema["properties"]["private"]["description"],
+            "Read by Smallweb."
+        );
+    }
+}
@@ -1,10 +1,13 @@
-use crate::debug_log;
+use crate::config::{parse_config, SmallwebConfig, CONFIG_FILE_NAMES};
+use crate::proxy::Upstream;
+use crate::settings::Settings;
 use serde::Deserialize;
 use std::env;
 use std::ffi::OsString;
 use std::fs;
 use std::os::unix::fs::MetadataExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[derive(Deserialize, Debug, PartialEq, Clone)]
 #[allow(dead_code)] // command is unused for now
@@ -14,28 +17,72 @@ pub struct DenoArgs {
     pub port: u16,
 }
 
-#[derive(Deserialize, Debug, PartialEq, Clone)]
-pub struct SmallwebConfig {
-    pub exec: String,
-}
+/// Set to the adapter's PID right before it execs deno. Exec keeps the PID,
+/// so finding our own PID here on startup means "deno" resolved back to the
+/// adapter and we would loop forever.
+pub const EXEC_MARKER_ENV: &str = "SMALLWEB_ADAPTER_EXEC_PID";
 
 #[derive(Debug, PartialEq)]
 pub enum Action {
-    Exec(SmallwebConfig, DenoArgs),
-    ExecDeno { new_path: Option<OsString> },
+    Exec(Box<SmallwebConfig>, DenoArgs),
+    /// Forward the port to `upstream` instead of launching anything.
+    Proxy {
+        upstream: Upstream,
+        idle_timeout: Option<Duration>,
+        deno_args: DenoArgs,
+    },
+    /// Run the real deno: `deno` is its explicitly configured location,
+    /// otherwise it is looked up in `new_path` (or the unchanged `PATH`).
+    ExecDeno {
+        new_path: Option<OsString>,
+        deno: Option<PathBuf>,
+    },
+    InvalidConfig(PathBuf, String),
+    Abort(String),
 }
 
-pub fn decide_action(args: &[String], path_var: &str) -> (Action, PathBuf) {
+impl Action {
+    /// Short name of the variant, for logs.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Action::Exec(config, _) if config.serves_static() => "static",
+            Action::Exec(..) => "exec",
+            Action::Proxy { .. } => "proxy",
+            Action::ExecDeno { .. } => "exec_deno",
+            Action::InvalidConfig(..) => "invalid_config",
+            Action::Abort(_) => "abort",
+        }
+    }
+}
+
+pub fn decide_action(
+    args: &[String],
+    path_var: &str,
+    app_dir: Option<&Path>,
+    settings: &Settings,
+) -> (Action, PathBuf) {
     debug_log!("decide_action called with args: {:?}", args);
     debug_log!("original PATH: {}", path_var);
+    debug_log!("app_dir: {:?}", app_dir);
     let own_abs_path = args
-        .get(0)
+        .first()
         .and_then(|p| std::fs::canonicalize(p).ok())
         .expect("Failed to get absolute path of executable from args[0]");
     debug_log!("own_abs_path: {:?}", own_abs_path);
 
+    if let Some(deno) = &settings.deno {
+        debug_log!("configured deno: {}", deno.display());
+        if is_same_file(deno, &own_abs_path) {
+            let message = format!(
+                "configured deno {} is the adapter itself, refusing to exec it",
+                deno.display()
+            );
+            return (Action::Abort(message), own_abs_path);
+        }
+    }
+
     let mut is_shadowing_deno = false;
-    if let Some(executable_path) = args.get(0) {
+    if let Some(executable_path) = args.first() {
         if let Some(file_name) = std::path::Path::new(executable_path).file_name() {
             is_shadowing_deno = file_name == "deno";
         }
@@ -68,12 +115,19 @@ pub fn decide_action(args: &[String], path_var: &str) -> (Action, PathBuf) {
     };
 
     let fallback = || {
-        (
-            Action::ExecDeno {
+        let action = match &settings.deno {
+            // An explicit deno needs no PATH surgery, and stripping our own
+            // directory could hide other tools that live next to it.
+            Some(deno) => Action::ExecDeno {
+                new_path: None,
+                deno: Some(deno.clone()),
+            },
+            None => Action::ExecDeno {
                 new_path: create_new_path(),
+                deno: None,
             },
-            own_abs_path.clone(),
-        )
+        };
+        (action, own_abs_path.clone())
     };
 
     let last_arg = if let Some(arg) = args.last() {
@@ -92,41 +146,111 @@ pub fn decide_action(args: &[String], path_var: &str) -> (Action, PathBuf) {
     };
     debug_log!("deno_args: {:?}", deno_args);
 
-    if let Some(path_str) = deno_args.entrypoint.strip_prefix("file://") {
+    let entrypoint_dir = if let Some(path_str) = deno_args.entrypoint.strip_prefix("file://") {
         debug_log!("path_str: {}", path_str);
 
         let entrypoint_path = PathBuf::from(path_str);
-        let app_dir = if entrypoint_path.is_dir() {
-            Some(entrypoint_path.as_path())
+        if entrypoint_path.is_dir() {
+            Some(entrypoint_path)
         } else {
-            entrypoint_path.parent()
-        };
+            entrypoint_path.parent().map(Path::to_path_buf)
+        }
+    } else {
+        debug_log!(
+            "entrypoint {} doesn't start with file://, only checking app dir.",
+            deno_args.entrypoint
+        );
+        None
+    };
 
-        if let Some(dir) = app_dir {
-            let config_path = dir.join("smallweb.json");
-            debug_log!("checking for config file at {}", config_path.display());
+    let config_path = if let Some(path) = find_config(entrypoint_dir.as_deref(), app_dir) {
+        path
+    } else {
+        debug_log!("No config file found, falling back.");
+        return fallback();
+    };
 
-            if let Ok(file_content) = fs::read_to_string(config_path) {
-                debug_log!("file_content: {}", file_content);
+    if let Ok(file_content) = fs::read_to_string(&config_path) {
+        trace_log!("file_content: {}", file_content);
 
-                if let Ok(config) = serde_json::from_str::<SmallwebConfig>(&file_content) {
-                    debug_log!(
-                        "Successfully parsed file_content as SmallwebConfig, returning Action::Exec."
-                    );
-                    return (Action::Exec(config, deno_args), own_abs_path);
-                } else {
-                    debug_log!("Failed to parse file_content as SmallwebConfig, falling back.");
+        match parse_config(&file_content) {
+            Ok(Some(config)) => {
+                if let Some(spec) = &config.proxy {
+                    let action = match Upstream::parse(spec) {
+                        Ok(upstream) => Action::Proxy {
+                            upstream,
+                            idle_timeout: config.idle_timeout.map(Duration::from_secs),
+                            deno_args,
+                        },
+                        Err(e) => Action::InvalidConfig(config_path, format!("`proxy`: {}", e)),
+                    };
+                    return (action, own_abs_path);
                 }
-            } else {
-                debug_log!("Could not read config file, falling back.");
+                info_log!(
+                    "Successfully parsed file_content as SmallwebConfig, returning Action::Exec."
+                );
+                return (Action::Exec(Box::new(config), deno_args), own_abs_path);
+            }
+            Ok(None) => {
+                debug_log!("Config file has no adapter settings, falling back.");
+            }
+            Err(errors) => {
+                let message = errors
+                    .iter()
+                    .map(|e| e.to_string())
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                warn_log!(
+                    "Failed to parse file_content as SmallwebConfig: {}",
+                    message
+                );
+                return (Action::InvalidConfig(config_path, message), own_abs_path);
             }
         }
-        fallback()
     } else {
-        debug_log!(
-            "entrypoint {} doesn't start with file://, falling back.",
-            deno_args.entrypoint
-        );
-        fallback()
+        debug_log!("Could not read config file, falling back.");
     }
+    fallback()
+}
+
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (a.metadata(), b.metadata()) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+/// Looks for `smallweb.json` (or `smallweb.jsonc`) starting at `start` and walking up through its
+/// parents until `app_root` is reached. Without an `app_root` only `start`
+/// itself is checked; without a `start` (e.g. a `jsr:` entrypoint) only
+/// `app_root` is checked.
+pub fn find_config(start: Option<&Path>, app_root: Option<&Path>) -> Option<PathBuf> {
+    let app_root = app_root.and_then(|p| fs::canonicalize(p).ok());
+    let start = start.and_then(|p| fs::canonicalize(p).ok());
+
+    let dirs: Vec<PathBuf> = match (start, app_root) {
+        (Some(start), Some(root)) if start.starts_with(&root) => start
+            .ancestors()
+            .take_while(|dir| dir.starts_with(&root))
+            .map(Path::to_path_buf)
+            .collect(),
+        (Some(start), Some(root)) => {
+            debug_log!(
+                "entrypoint dir {} is outside app dir {}, checking both",
+                start.display(),
+                root.display()
+            );
+            vec![start, root]
+        }
+        (Some(start), None) => vec![start],
+        (None, Some(root)) => vec![root],
+        (None, None) => vec![],
+    };
+
+    dirs.iter()
+        .flat_map(|dir| CONFIG_FILE_NAMES.iter().map(move |name| dir.join(name)))
+        .find(|path| {
+            debug_log!("checking for config file at {}", path.display());
+            path.is_file()
+        })
 }
@@ -0,0 +1,259 @@
+use std::fmt;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+
+/// What went wrong while bwrap was still setting up the sandbox.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetupFailure {
+    /// Creating the user or PID namespace was refused.
+    Namespace,
+    /// A bind mount or the new root could not be set up.
+    Mount,
+    /// Any other `bwrap:` error, e.g. a missing command.
+    Other,
+}
+
+/// Why the sandboxed process ended, as far as we can tell.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExitReason {
+    /// The app exited on its own with this code.
+    Code(i32),
+    /// The app was killed by a signal; `oom` when the OOM killer fired in
+    /// our cgroup meanwhile.
+    Signal { signal: i32, oom: bool },
+    /// bwrap failed before the app started, with its error line.
+    Setup {
+        failure: SetupFailure,
+        message: String,
+    },
+}
+
+impl ExitReason {
+    /// Classifies `status`. `stderr_tail` is the last few lines the sandbox
+    /// wrote to stderr, where bwrap reports its own errors.
+    pub fn classify(status: ExitStatus, stderr_tail: &[String], oom_killed: bool) -> ExitReason {
+        let bwrap_error = stderr_tail
+            .iter()
+            .rev()
+            .find_map(|line| line.strip_prefix("bwrap: "));
+        // bwrap and sh pass on the app being killed as exit code 128 + signal.
+        let reported_signal = status
+            .code()
+            .filter(|code| bwrap_error.is_none() && (129..=128 + libc::SIGRTMAX()).contains(code))
+            .map(|code| code - 128);
+        if let Some(signal) = status.signal().or(reported_signal) {
+            return ExitReason::Signal {
+                signal,
+                oom: oom_killed && signal == libc::SIGKILL,
+            };
+        }
+        match (status.code(), bwrap_error) {
+            (Some(code), Some(message)) if code != 0 => {
+                let lower = message.to_lowercase();
+                let failure = if lower.contains("namespace") || lower.contains("uid map") {
+                    SetupFailure::Namespace
+                } else if lower.contains("mount")
+                    || lower.contains("source path")
+                    || lower.contains("pivot_root")
+                {
+                    SetupFailure::Mount
+                } else {
+                    SetupFailure::Other
+                };
+                ExitReason::Setup {
+                    failure,
+                    message: message.to_string(),
+                }
+            }
+            (code, _) => ExitReason::Code(code.unwrap_or(1)),
+        }
+    }
+
+    /// Short machine readable name, used in the `child_exit` event.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ExitReason::Code(_) => "exit",
+            ExitReason::Signal { oom: true, .. } => "oom",
+            ExitReason::Signal { .. } => "signal",
+            ExitReason::Setup {
+                failure: SetupFailure::Namespace,
+                ..
+            } => "sandbox_namespace",
+            ExitReason::Setup {
+                failure: SetupFailure::Mount,
+                ..
+            } => "sandbox_mount",
+            ExitReason::Setup {
+                failure: SetupFailure::Other,
+                ..
+            } => "sandbox_setup",
+        }
+    }
+
+    /// The code the adapter exits with: the app's own code, or the shell
+    /// convention of 128 + signal.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ExitReason::Code(code) => *code,
+            ExitReason::Signal { signal, .. } => 128 + signal,
+            ExitReason::Setup { .. } => 1,
+        }
+    }
+}
+
+impl fmt::Display for ExitReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitReason::Code(code) => write!(f, "app exited with code {}", code),
+            ExitReason::Signal { signal, oom } => {
+                write!(
+                    f,
+                    "app was killed by signal {} ({})",
+                    signal,
+                    signal_name(*signal)
+                )?;
+                if *oom {
+                    write!(f, ", by the OOM killer")?;
+                }
+                Ok(())
+            }
+            ExitReason::Setup { failure, message } => {
+                let what = match failure {
+                    SetupFailure::Namespace => "sandbox namespaces could not be created",
+                    SetupFailure::Mount => "sandbox mounts could not be set up",
+                    SetupFailure::Other => "sandbox setup failed",
+                };
+                write!(f, "{}: bwrap: {}", what, message)?;
+                if *failure == SetupFailure::Namespace {
+                    write!(f, " (are unprivileged user namespaces enabled?)")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A multi-line report for Smallweb's error output: the reason, when it
+/// happened and the app's last words on stderr.
+pub fn summary(reason: &ExitReason, early: bool, stderr_tail: &[String]) -> String {
+    // bwrap's own error already says everything, and is always early.
+    if let ExitReason::Setup { .. } = reason {
+        return format!("error: {}", reason);
+    }
+    let when = if early { "before READY" } else { "after READY" };
+    let mut summary = format!("error: {} {}", reason, when);
+    if !stderr_tail.is_empty() {
+        summary.push_str("\nlast lines of stderr:");
+        for line in stderr_tail {
+            summary.push_str("\n  | ");
+            summary.push_str(line);
+        }
+    }
+    summary
+}
+
+pub fn signal_name(signal: i32) -> &'static str {
+    match signal {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGUSR1 => "SIGUSR1",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGUSR2 => "SIGUSR2",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGXCPU => "SIGXCPU",
+        libc::SIGXFSZ => "SIGXFSZ",
+        libc::SIGSYS => "SIGSYS",
+        _ => "unknown signal",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_classify() {
+        let exited = |code: i32| ExitStatus::from_raw(code << 8);
+
+        let reason = ExitReason::classify(exited(3), &lines(&["+ ./server", "boom"]), false);
+        assert_eq!(reason, ExitReason::Code(3));
+        assert_eq!(reason.exit_code(), 3);
+
+        let reason = ExitReason::classify(ExitStatus::from_raw(libc::SIGSEGV), &[], true);
+        assert_eq!(reason.kind(), "signal");
+        assert_eq!(reason.exit_code(), 139);
+        assert_eq!(reason.to_string(), "app was killed by signal 11 (SIGSEGV)");
+
+        let reason = ExitReason::classify(ExitStatus::from_raw(libc::SIGKILL), &[], true);
+        assert_eq!(reason.kind(), "oom");
+        assert!(reason.to_string().ends_with("(SIGKILL), by the OOM killer"));
+
+        // As reported through bwrap.
+        let reason = ExitReason::classify(exited(137), &lines(&["+ ./server"]), true);
+        assert_eq!(
+            reason,
+            ExitReason::Signal {
+                signal: libc::SIGKILL,
+                oom: true
+            }
+        );
+        assert_eq!(reason.exit_code(), 137);
+        let reason = ExitReason::classify(exited(139), &[], false);
+        assert_eq!(reason.to_string(), "app was killed by signal 11 (SIGSEGV)");
+        assert_eq!(reason.exit_code(), 139);
+        let tail = lines(&["bwrap: execvp ./server: Permission denied"]);
+        assert_eq!(
+            ExitReason::classify(exited(255), &tail, false).kind(),
+            "sandbox_setup"
+        );
+
+        let tail = lines(&["bwrap: No permissions to creating new namespace, likely because the kernel does not allow non-privileged user namespaces."]);
+        let reason = ExitReason::classify(exited(1), &tail, false);
+        assert_eq!(reason.kind(), "sandbox_namespace");
+        assert_eq!(reason.exit_code(), 1);
+
+        let tail = lines(&["bwrap: Can't find source path /srv/data: No such file or directory"]);
+        assert_eq!(
+            ExitReason::classify(exited(1), &tail, false).kind(),
+            "sandbox_mount"
+        );
+        let tail = lines(&["bwrap: execvp /bin/sh: No such file or directory"]);
+        assert_eq!(
+            ExitReason::classify(exited(1), &tail, false).kind(),
+            "sandbox_setup"
+        );
+    }
+
+    #[test]
+    fn test_summary() {
+        let tail = lines(&["Traceback (most recent call last):", "KeyError: 'PORT'"]);
+        assert_eq!(
+            summary(&ExitReason::Code(1), true, &tail),
+            "error: app exited with code 1 before READY\nlast lines of stderr:\n  | Traceback (most recent call last):\n  | KeyError: 'PORT'"
+        );
+        assert_eq!(
+            summary(
+                &ExitReason::Signal {
+                    signal: 15,
+                    oom: false
+                },
+                false,
+                &[]
+            ),
+            "error: app was killed by signal 15 (SIGTERM) after READY"
+        );
+    }
+}
@@ -0,0 +1,269 @@
+use crate::linux;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// PATH used when Smallweb strips the environment and no source sets one.
+pub const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+config_struct! {
+    /// How to rebuild `PATH` when Smallweb launches the adapter without one.
+    #[derive(Default)]
+    pub struct EnvPolicy {
+        /// PATH to start from. Defaults to the usual system directories.
+        pub default_path: Option<String>,
+        /// Files read in order for static `PATH=...` / `export PATH=...`
+        /// assignments, e.g. `/etc/environment` or a login profile snippet.
+        /// `$PATH`, `${PATH}` and `$HOME` are expanded; any other line, and
+        /// any assignment inside `if`, `case` or a loop, is ignored, nothing
+        /// is executed. Defaults to `["/etc/environment"]`.
+        pub sources: Option<Vec<PathBuf>>,
+        /// Cache the recovered PATH until one of the sources changes.
+        /// Defaults to true.
+        pub cache: Option<bool>,
+    }
+}
+
+impl EnvPolicy {
+    fn sources(&self) -> Vec<PathBuf> {
+        self.sources
+            .clone()
+            .unwrap_or_else(|| vec![PathBuf::from("/etc/environment")])
+    }
+}
+
+/// Recovers PATH according to `policy`, going through the cache file unless
+/// caching is disabled.
+pub fn recover_path(policy: &EnvPolicy) -> String {
+    if !policy.cache.unwrap_or(true) {
+        return resolve_path(policy);
+    }
+    let cache = match linux::user_private_dir() {
+        Ok(dir) => dir.join("path.cache"),
+        Err(e) => {
+            warn_log!("not caching PATH: {}", e);
+            return resolve_path(policy);
+        }
+    };
+    // A changed policy must not reuse a PATH recovered under the old one.
+    let key = format!("{:?}", policy);
+    if let Some(path) = read_cache(&cache, &key, &policy.sources()) {
+        debug_log!("using cached PATH from {}", cache.display());
+        return path;
+    }
+    let path = resolve_path(policy);
+    if let Err(e) = write_cache(&cache, &key, &path) {
+        debug_log!("failed to write PATH cache {}: {}", cache.display(), e);
+    }
+    path
+}
+
+/// Computes PATH from the default and the configured sources without
+/// touching the cache.
+pub fn resolve_path(policy: &EnvPolicy) -> String {
+    let mut path = policy
+        .default_path
+        .clone()
+        .unwrap_or_else(|| DEFAULT_PATH.to_string());
+    let home = env::var("HOME").ok();
+
+    for source in policy.sources() {
+        let Ok(content) = fs::read_to_string(&source) else {
+            debug_log!("skipping unreadable PATH source {}", source.display());
+            continue;
+        };
+        // Assignments inside `if`, `case` or a loop depend on what the
+        // shell would find at run time, so only top-level ones count.
+        let mut depth = 0usize;
+        for line in content.lines() {
+            if depth == 0 {
+                if let Some(value) = parse_path_assignment(line, &path, home.as_deref()) {
+                    debug_log!("PATH from {}: {}", source.display(), value);
+                    path = value;
+                }
+            }
+            depth = nesting_depth(line, depth);
+        }
+    }
+    path
+}
+
+/// The depth of shell compound commands after `line`, starting at `depth`.
+fn nesting_depth(line: &str, depth: usize) -> usize {
+    let code = line.split('#').next().unwrap_or_default();
+    code.split(|c: char| c.is_whitespace() || c == ';')
+        .fold(depth, |depth, word| match word {
+            "if" | "case" | "for" | "while" | "until" | "select" => depth + 1,
+            "fi" | "esac" | "done" => depth.saturating_sub(1),
+            _ => depth,
+        })
+}
+
+/// Evaluates a single `PATH=...` or `export PATH=...` line. Returns `None` for
+/// anything else, or for values we can't expand without running a shell.
+fn parse_path_assignment(line: &str, current: &str, home: Option<&str>) -> Option<String> {
+    let line = line.trim();
+    let line = line.strip_prefix("export ").unwrap_or(line).trim_start();
+    let value = line.strip_prefix("PATH=")?.trim_end();
+
+    let value = if let Some(inner) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        inner
+    } else if let Some(inner) = value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')) {
+        // Single quotes don't expand anything.
+        return (!inner.is_empty()).then(|| inner.to_string());
+    } else {
+        value
+    };
+    if value.contains('`') || value.contains("$(") {
+        return None;
+    }
+
+    let mut expanded = value.replace("${PATH}", current).replace("$PATH", current);
+    if expanded.contains("$HOME") || expanded.contains("${HOME}") {
+        let home = home?;
+        expanded = expanded.replace("${HOME}", home).replace("$HOME", home);
+    }
+    if expanded.contains('$') || expanded.is_empty() {
+        return None;
+    }
+    Some(expanded)
+}
+
+/// Returns the cached PATH if it was recovered under the policy `key` and the
+/// cache is newer than every source. A cache someone else could have written
+/// is ignored: it decides which deno and which apps run.
+fn read_cache(cache: &Path, key: &str, sources: &[PathBuf]) -> Option<String> {
+    if !linux::is_own_private_file(cache) {
+        return None;
+    }
+    let cached_at = fs::metadata(cache).and_then(|m| m.modified()).ok()?;
+    let stale = sources.iter().any(|source| {
+        fs::metadata(source)
+            .and_then(|m| m.modified())
+            .is_ok_and(|modified| modified >= cached_at)
+    });
+    if stale {
+        return None;
+    }
+    let content = fs::read_to_string(cache).ok()?;
+    let (cached_key, path) = content.split_once('\n')?;
+    (cached_key == key && !path.is_empty()).then(|| path.to_string())
+}
+
+fn write_cache(cache: &Path, key: &str, path: &str) -> std::io::Result<()> {
+    let tmp = cache.with_extension(format!("{}.tmp", std::process::id()));
+    let mut file = fs::File::options()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp)?;
+    file.write_all(format!("{}\n{}", key, path).as_bytes())?;
+    fs::rename(&tmp, cache)?;
+    // Make sure the cache counts as newer than sources edited this second.
+    let file = fs::File::options().write(true).open(cache)?;
+    file.set_modified(SystemTime::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_path_assignment() {
+        let current = "/usr/bin:/bin";
+        assert_eq!(
+            parse_path_assignment(r#"PATH="/usr/local/bin:/usr/bin""#, current, None),
+            Some("/usr/local/bin:/usr/bin".to_string())
+        );
+        assert_eq!(
+            parse_path_assignment(
+                "export PATH=$HOME/.deno/bin:$PATH",
+                current,
+                Some("/home/web")
+            ),
+            Some("/home/web/.deno/bin:/usr/bin:/bin".to_string())
+        );
+        assert_eq!(
+            parse_path_assignment(r#"export PATH="${PATH}:/opt/bin""#, current, None),
+            Some("/usr/bin:/bin:/opt/bin".to_string())
+        );
+        assert_eq!(parse_path_assignment("PATH=$HOME/bin", current, None), None);
+        assert_eq!(
+            parse_path_assignment("PATH=$(brew --prefix)/bin", current, None),
+            None
+        );
+        assert_eq!(
+            parse_path_assignment("MANPATH=/usr/man", current, None),
+            None
+        );
+        assert_eq!(parse_path_assignment("# PATH=/nope", current, None), None);
+    }
+
+    #[test]
+    fn test_resolve_path_from_sources() {
+        let temp_dir = tempdir().unwrap();
+        let environment = temp_dir.path().join("environment");
+        fs::write(
+            &environment,
+            "LANG=C.UTF-8\nPATH=\"/usr/local/bin:/usr/bin:/bin\"\n",
+        )
+        .unwrap();
+        let profile = temp_dir.path().join("profile");
+        fs::write(
+            &profile,
+            "# deno\nif [ -d /opt ]; then\n  export PATH=\"/opt/deno/bin:$PATH\"\nfi\n\
+             case $- in\n  *i*) PATH=/interactive ;;\nesac\n\
+             for d in /opt/*; do\nPATH=\"$d:$PATH\"\ndone\n\
+             export PATH=\"$PATH:/opt/bin\"\n",
+        )
+        .unwrap();
+
+        let policy = EnvPolicy {
+            default_path: Some("/bin".to_string()),
+            sources: Some(vec![environment, temp_dir.path().join("missing"), profile]),
+            cache: Some(false),
+        };
+        assert_eq!(
+            resolve_path(&policy),
+            "/usr/local/bin:/usr/bin:/bin:/opt/bin"
+        );
+
+        let policy = EnvPolicy {
+            sources: Some(vec![]),
+            ..EnvPolicy::default()
+        };
+        assert_eq!(resolve_path(&policy), DEFAULT_PATH);
+    }
+
+    #[test]
+    fn test_cache_invalidation() {
+        let temp_dir = tempdir().unwrap();
+        let source = temp_dir.path().join("environment");
+        fs::write(&source, "PATH=/a\n").unwrap();
+        let cache = temp_dir.path().join("path.cache");
+
+        let sources = [source.clone()];
+
+        assert_eq!(read_cache(&cache, "key", &sources), None);
+        write_cache(&cache, "key", "/a").unwrap();
+        assert_eq!(read_cache(&cache, "key", &sources), Some("/a".to_string()));
+        assert_eq!(read_cache(&cache, "other policy", &sources), None);
+
+        // Anyone who could have written it could have picked the PATH.
+        fs::set_permissions(&cache, fs::Permissions::from_mode(0o666)).unwrap();
+        assert_eq!(read_cache(&cache, "key", &sources), None);
+        fs::set_permissions(&cache, fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(read_cache(&cache, "key", &sources), Some("/a".to_string()));
+
+        fs::write(&source, "PATH=/b\n").unwrap();
+        let file = fs::File::options().write(true).open(&source).unwrap();
+        file.set_modified(SystemTime::now() + std::time::Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(read_cache(&cache, "key", &sources), None);
+    }
+}
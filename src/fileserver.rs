@@ -0,0 +1,825 @@
+use crate::history::Invocation;
+use crate::proxy::{self, Activity};
+use serde::{de, Deserialize, Deserializer};
+use serde_json::{json, Value};
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// How long a keep-alive connection may sit idle between requests.
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(15);
+/// Upper bound for the request line and headers together.
+const MAX_HEAD_BYTES: usize = 64 * 1024;
+/// Connections served at once, each by a thread of its own. Any beyond are
+/// answered with a 503 straight away.
+const MAX_CONNECTIONS: usize = 256;
+
+config_struct! {
+    /// A directory served by the adapter itself.
+    pub struct StaticOptions {
+        /// Directory to serve, relative to the app dir and inside it.
+        pub dir: PathBuf,
+        /// Answer unknown paths without a file extension with the root
+        /// `index.html`, for single-page apps doing their own routing.
+        pub spa: Option<bool>,
+    }
+}
+
+/// `static` in smallweb.json: a directory, or `{"dir": ..., "spa": true}`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StaticSite {
+    Dir(PathBuf),
+    Options(StaticOptions),
+}
+
+impl StaticSite {
+    /// What the `static` runtime serves: `public/` if there is one, else
+    /// the app dir.
+    pub fn default_for(app_dir: &Path) -> StaticSite {
+        let dir = if app_dir.join("public").is_dir() {
+            "public"
+        } else {
+            "."
+        };
+        StaticSite::Dir(PathBuf::from(dir))
+    }
+
+    pub fn dir(&self) -> &Path {
+        match self {
+            StaticSite::Dir(dir) => dir,
+            StaticSite::Options(options) => &options.dir,
+        }
+    }
+
+    pub fn spa(&self) -> bool {
+        match self {
+            StaticSite::Dir(_) => false,
+            StaticSite::Options(options) => options.spa.unwrap_or(false),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for StaticSite {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<StaticSite, D::Error> {
+        match Value::deserialize(deserializer)? {
+            Value::String(dir) => Ok(StaticSite::Dir(PathBuf::from(dir))),
+            value @ Value::Object(_) => serde_json::from_value(value)
+                .map(StaticSite::Options)
+                .map_err(de::Error::custom),
+            _ => Err(de::Error::custom(
+                "expected a directory or {\"dir\": ..., \"spa\": ...}",
+            )),
+        }
+    }
+}
+
+impl crate::schema::JsonSchema for StaticSite {
+    fn schema() -> Value {
+        json!({ "oneOf": [{ "type": "string" }, StaticOptions::schema()] })
+    }
+}
+
+/// The served directory, resolved and checked once at startup.
+struct Site {
+    root: PathBuf,
+    spa: bool,
+}
+
+impl Site {
+    /// Resolves `site` against `app_dir`, refusing directories outside it.
+    fn resolve(site: &StaticSite, app_dir: &Path) -> Result<Site, String> {
+        let app_dir =
+            fs::canonicalize(app_dir).map_err(|e| format!("{}: {}", app_dir.display(), e))?;
+        let root = app_dir.join(site.dir());
+        let root = fs::canonicalize(&root).map_err(|e| format!("{}: {}", root.display(), e))?;
+        if !root.starts_with(&app_dir) || !root.is_dir() {
+            return Err(format!(
+                "{} is not a directory inside {}",
+                root.display(),
+                app_dir.display()
+            ));
+        }
+        Ok(Site {
+            root,
+            spa: site.spa(),
+        })
+    }
+
+    /// Maps a request path onto a file or directory below the root. `None`
+    /// for anything that doesn't exist, is hidden or leads outside.
+    fn lookup(&self, path: &[u8]) -> Option<PathBuf> {
+        let mut resolved = self.root.clone();
+        for segment in path.split(|&b| b == b'/') {
+            match segment {
+                b"" | b"." => {}
+                // Dotfiles such as `.env` are never served, `..` never climbs.
+                [b'.', ..] => return None,
+                segment => resolved.push(OsStr::from_bytes(segment)),
+            }
+        }
+        // Symlinks may point anywhere; only follow those that stay inside.
+        let resolved = fs::canonicalize(resolved).ok()?;
+        resolved.starts_with(&self.root).then_some(resolved)
+    }
+
+    fn respond(&self, request: &Request) -> Response {
+        if request.method != "GET" && request.method != "HEAD" {
+            return Response::text(405, "method not allowed").header("Allow", "GET, HEAD");
+        }
+        let target = request.target.split(['?', '#']).next().unwrap_or_default();
+        let Some(path) = percent_decode(target).filter(|path| path.starts_with(b"/")) else {
+            return Response::text(400, "bad request");
+        };
+
+        let Some(found) = self.lookup(&path) else {
+            let last = path.rsplit(|&b| b == b'/').next().unwrap_or_default();
+            if self.spa && !last.contains(&b'.') {
+                // Through `lookup` too: index.html may be a symlink.
+                if let Some(index) = self.lookup(b"/index.html").filter(|index| index.is_file()) {
+                    return serve_file(&index, request);
+                }
+            }
+            return Response::text(404, "not found");
+        };
+        if !found.is_dir() {
+            return serve_file(&found, request);
+        }
+        if !path.ends_with(b"/") {
+            // Relative links in the index resolve against the directory.
+            return Response::text(301, "moved permanently")
+                .header("Location", format!("{}/", target));
+        }
+        let index = found.join("index.html");
+        if index.is_file() {
+            return serve_file(&index, request);
+        }
+        listing(&found, &String::from_utf8_lossy(&path))
+    }
+}
+
+/// A parsed request head; bodies are never read.
+#[derive(Debug, Default)]
+struct Request {
+    method: String,
+    target: String,
+    /// Header names lowercased.
+    headers: Vec<(String, String)>,
+    keep_alive: bool,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Reads one request head, `None` when the client closed the connection.
+fn read_request(reader: &mut impl BufRead) -> io::Result<Option<Request>> {
+    let mut lines = Vec::new();
+    let mut size = 0;
+    loop {
+        let mut line = String::new();
+        let limit = (MAX_HEAD_BYTES - size + 1) as u64;
+        if reader.by_ref().take(limit).read_line(&mut line)? == 0 {
+            if lines.is_empty() {
+                return Ok(None);
+            }
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        size += line.len();
+        if size > MAX_HEAD_BYTES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request head too large",
+            ));
+        }
+        let line = line.trim_end_matches(['\r', '\n']).to_string();
+        if line.is_empty() {
+            if lines.is_empty() {
+                // Stray newlines between requests are allowed.
+                continue;
+            }
+            break;
+        }
+        lines.push(line);
+    }
+
+    let mut request_line = lines[0].split_whitespace();
+    let (Some(method), Some(target), Some(version)) = (
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+    ) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "malformed request line",
+        ));
+    };
+    let headers: Vec<(String, String)> = lines[1..]
+        .iter()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+    let mut request = Request {
+        method: method.to_string(),
+        target: target.to_string(),
+        headers,
+        keep_alive: false,
+    };
+    let connection = request
+        .header("connection")
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    // A body we won't read would be taken for the next request.
+    let has_body = request
+        .header("content-length")
+        .is_some_and(|len| len != "0")
+        || request.header("transfer-encoding").is_some();
+    request.keep_alive = !has_body
+        && match version {
+            "HTTP/1.1" => connection != "close",
+            _ => connection == "keep-alive",
+        };
+    Ok(Some(request))
+}
+
+enum Body {
+    Bytes(Vec<u8>),
+    /// `len` bytes of the file, starting at `start`.
+    File {
+        file: File,
+        start: u64,
+        len: u64,
+    },
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Body,
+}
+
+impl Response {
+    fn text(status: u16, text: &str) -> Response {
+        Response {
+            status,
+            headers: vec![("Content-Type", "text/plain; charset=utf-8".to_string())],
+            body: Body::Bytes(format!("{}\n", text).into_bytes()),
+        }
+    }
+
+    fn header(mut self, name: &'static str, value: impl Into<String>) -> Response {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    fn len(&self) -> u64 {
+        match &self.body {
+            Body::Bytes(bytes) => bytes.len() as u64,
+            Body::File { len, .. } => *len,
+        }
+    }
+
+    fn write_to(self, out: &mut impl Write, head_only: bool, keep_alive: bool) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        // A 304 describes the representation it stands in for, so a length
+        // of 0 would be wrong; it simply has none.
+        if self.status != 304 {
+            head.push_str(&format!("Content-Length: {}\r\n", self.len()));
+        }
+        let connection = if keep_alive { "keep-alive" } else { "close" };
+        head.push_str(&format!("Connection: {}\r\n\r\n", connection));
+        out.write_all(head.as_bytes())?;
+        if !head_only {
+            match self.body {
+                Body::Bytes(bytes) => out.write_all(&bytes)?,
+                Body::File {
+                    mut file,
+                    start,
+                    len,
+                } => {
+                    file.seek(SeekFrom::Start(start))?;
+                    io::copy(&mut file.take(len), out)?;
+                }
+            }
+        }
+        out.flush()
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        416 => "Range Not Satisfiable",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Serves `path` whole, as a single byte range or as not modified.
+fn serve_file(path: &Path, request: &Request) -> Response {
+    let opened = File::open(path).and_then(|file| {
+        let metadata = file.metadata()?;
+        Ok((file, metadata))
+    });
+    let (file, metadata) = match opened {
+        Ok(opened) => opened,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Response::text(404, "not found"),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            return Response::text(403, "forbidden")
+        }
+        Err(e) => {
+            warn_log!("failed to open {}: {}", path.display(), e);
+            return Response::text(500, "internal server error");
+        }
+    };
+    let size = metadata.len();
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    let etag = format!("\"{:x}-{:x}\"", mtime.as_nanos(), size);
+
+    let headers = vec![
+        ("Content-Type", mime_type(path).to_string()),
+        ("ETag", etag.clone()),
+        ("Accept-Ranges", "bytes".to_string()),
+        ("Cache-Control", "no-cache".to_string()),
+    ];
+    let not_modified = request.header("if-none-match").is_some_and(|tags| {
+        tags.split(',')
+            .any(|tag| tag.trim() == etag || tag.trim() == "*")
+    });
+    if not_modified {
+        return Response {
+            status: 304,
+            headers,
+            body: Body::Bytes(Vec::new()),
+        };
+    }
+
+    // A stale If-Range asks for the whole new file rather than a piece.
+    let range = request
+        .header("range")
+        .filter(|_| request.header("if-range").is_none_or(|tag| tag == etag));
+    let (status, start, len) = match range.map(|range| parse_range(range, size)) {
+        Some(Some(Ok((start, end)))) => (206, start, end - start + 1),
+        Some(Some(Err(()))) => {
+            return Response::text(416, "range not satisfiable")
+                .header("Content-Range", format!("bytes */{}", size));
+        }
+        // No range, or one we don't support, such as several at once.
+        Some(None) | None => (200, 0, size),
+    };
+    let mut response = Response {
+        status,
+        headers,
+        body: Body::File { file, start, len },
+    };
+    if status == 206 {
+        response = response.header(
+            "Content-Range",
+            format!("bytes {}-{}/{}", start, start + len - 1, size),
+        );
+    }
+    response
+}
+
+/// Parses a single `bytes=` range against a file of `size` bytes into
+/// inclusive bounds. `None` for ranges to ignore, `Some(Err)` for ones that
+/// can't be satisfied.
+fn parse_range(header: &str, size: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (first, last) = spec.split_once('-')?;
+    let (first, last) = (first.trim(), last.trim());
+    let bounds = if first.is_empty() {
+        // `-N`: the last N bytes.
+        let suffix: u64 = last.parse().ok()?;
+        (size.saturating_sub(suffix), size.checked_sub(1))
+    } else {
+        let first: u64 = first.parse().ok()?;
+        let last = match last {
+            "" => size.saturating_sub(1),
+            last => last.parse::<u64>().ok()?.min(size.saturating_sub(1)),
+        };
+        (first, Some(last))
+    };
+    match bounds {
+        (first, Some(last)) if first <= last && first < size => Some(Ok((first, last))),
+        (first, Some(last)) if first > last && first < size => None,
+        _ => Some(Err(())),
+    }
+}
+
+fn mime_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "webmanifest" => "application/manifest+json",
+        "txt" | "md" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        "zip" => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
+
+/// An HTML index of `dir`, shown at `path` when it has no index.html.
+fn listing(dir: &Path, path: &str) -> Response {
+    let mut names: Vec<String> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .flatten()
+            .filter(|entry| !entry.file_name().as_bytes().starts_with(b"."))
+            .map(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if entry.path().is_dir() {
+                    format!("{}/", name)
+                } else {
+                    name
+                }
+            })
+            .collect(),
+        Err(_) => return Response::text(403, "forbidden"),
+    };
+    names.sort();
+    let title = html_escape(path);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{0}</title></head>\n<body><h1>{0}</h1>\n<ul>\n",
+        title
+    );
+    for name in names {
+        let name = html_escape(&name);
+        html.push_str(&format!("<li><a href=\"{0}\">{0}</a></li>\n", name));
+    }
+    html.push_str("</ul></body></html>\n");
+    Response {
+        status: 200,
+        headers: vec![("Content-Type", "text/html; charset=utf-8".to_string())],
+        body: Body::Bytes(html.into_bytes()),
+    }
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn percent_decode(text: &str) -> Option<Vec<u8>> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Some(decoded)
+}
+
+/// Answers requests on `stream` until the client is done with it.
+fn handle_connection(stream: TcpStream, site: &Site) -> io::Result<()> {
+    stream.set_read_timeout(Some(KEEP_ALIVE_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    while let Some(request) = read_request(&mut reader)? {
+        let response = site.respond(&request);
+        debug_log!("{} {} {}", request.method, request.target, response.status);
+        let head_only = request.method == "HEAD";
+        response.write_to(&mut writer, head_only, request.keep_alive)?;
+        if !request.keep_alive {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Answers every connection accepted on `listener` from a thread of its
+/// own, in the background.
+fn spawn_server(listener: TcpListener, site: Site, activity: Arc<Activity>) {
+    let site = Arc::new(site);
+    thread::spawn(move || loop {
+        match listener.accept() {
+            Ok((mut stream, _)) if activity.open() >= MAX_CONNECTIONS => {
+                debug_log!("refusing connection, {} already open", MAX_CONNECTIONS);
+                let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+                let _ =
+                    Response::text(503, "too many connections").write_to(&mut stream, false, false);
+            }
+            Ok((stream, _)) => {
+                let (site, activity) = (Arc::clone(&site), Arc::clone(&activity));
+                activity.opened();
+                thread::spawn(move || {
+                    if let Err(e) = handle_connection(stream, &site) {
+                        trace_log!("connection ended: {}", e);
+                    }
+                    activity.closed();
+                });
+            }
+            Err(e) => {
+                warn_log!("failed to accept connection: {}", e);
+                thread::sleep(Duration::from_millis(20));
+            }
+        }
+    });
+}
+
+/// Serves `site` from `app_dir` on `port` until a termination signal
+/// arrives, or no connection did for `idle_timeout`. Never returns.
+pub fn serve(
+    port: u16,
+    site: &StaticSite,
+    app_dir: &Path,
+    invocation: Invocation,
+    idle_timeout: Option<Duration>,
+) -> ! {
+    let site = match Site::resolve(site, app_dir) {
+        Ok(site) => site,
+        Err(e) => {
+            error_log!("invalid static directory: {}", e);
+            eprintln!("error: invalid static directory: {}", e);
+            invocation.finish(Some(1));
+            std::process::exit(1);
+        }
+    };
+    info_log!("serving {} on port {}", site.root.display(), port);
+    proxy::front(port, invocation, idle_timeout, |listener, activity| {
+        spawn_server(listener, site, activity)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn request(target: &str, headers: &[(&str, &str)]) -> Request {
+        Request {
+            method: "GET".to_string(),
+            target: target.to_string(),
+            headers: headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            keep_alive: true,
+        }
+    }
+
+    fn body(response: Response) -> String {
+        let mut out = Vec::new();
+        response.write_to(&mut out, false, false).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        text.split_once("\r\n\r\n").unwrap().1.to_string()
+    }
+
+    fn header<'a>(response: &'a Response, name: &str) -> Option<&'a str> {
+        response
+            .headers
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn site(spa: bool) -> (tempfile::TempDir, Site) {
+        let app_dir = tempdir().unwrap();
+        let public = app_dir.path().join("public");
+        fs::create_dir_all(public.join("docs")).unwrap();
+        fs::write(public.join("index.html"), "<h1>home</h1>").unwrap();
+        fs::write(public.join("app.js"), "console.log(1)").unwrap();
+        fs::write(public.join("docs/a b.txt"), "0123456789").unwrap();
+        fs::write(public.join(".env"), "TOKEN=x").unwrap();
+        fs::write(app_dir.path().join("smallweb.json"), "{}").unwrap();
+        std::os::unix::fs::symlink(app_dir.path().join("smallweb.json"), public.join("config"))
+            .unwrap();
+        let static_site = StaticSite::Options(StaticOptions {
+            dir: PathBuf::from("public"),
+            spa: Some(spa),
+        });
+        let site = Site::resolve(&static_site, app_dir.path()).unwrap();
+        (app_dir, site)
+    }
+
+    #[test]
+    fn test_files_and_directories() {
+        let (_app_dir, site) = site(false);
+
+        let response = site.respond(&request("/", &[]));
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            header(&response, "Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(body(response), "<h1>home</h1>");
+        let response = site.respond(&request("/app.js?v=2", &[]));
+        assert_eq!(
+            header(&response, "Content-Type"),
+            Some("text/javascript; charset=utf-8")
+        );
+
+        let response = site.respond(&request("/docs", &[]));
+        assert_eq!(
+            (response.status, header(&response, "Location")),
+            (301, Some("/docs/"))
+        );
+        let listing = body(site.respond(&request("/docs/", &[])));
+        assert!(listing.contains("<a href=\"a b.txt\">a b.txt</a>"));
+        assert_eq!(
+            body(site.respond(&request("/docs/a%20b.txt", &[]))),
+            "0123456789"
+        );
+
+        for target in [
+            "/.env",
+            "/../smallweb.json",
+            "/docs/../../smallweb.json",
+            "/config",
+            "/missing",
+        ] {
+            assert_eq!(
+                site.respond(&request(target, &[])).status,
+                404,
+                "{}",
+                target
+            );
+        }
+        let mut post = request("/", &[]);
+        post.method = "POST".to_string();
+        assert_eq!(site.respond(&post).status, 405);
+    }
+
+    #[test]
+    fn test_etag_and_range() {
+        let (_app_dir, site) = site(false);
+        let response = site.respond(&request("/docs/a%20b.txt", &[]));
+        let etag = header(&response, "ETag").unwrap().to_string();
+
+        let response = site.respond(&request("/docs/a%20b.txt", &[("if-none-match", &etag)]));
+        assert_eq!(response.status, 304);
+        let mut out = Vec::new();
+        response.write_to(&mut out, false, true).unwrap();
+        let head = String::from_utf8(out).unwrap();
+        assert!(head.contains(&format!("ETag: {}\r\n", etag)));
+        assert!(!head.contains("Content-Length"), "{}", head);
+
+        let response = site.respond(&request("/docs/a%20b.txt", &[("range", "bytes=2-4")]));
+        assert_eq!(response.status, 206);
+        assert_eq!(header(&response, "Content-Range"), Some("bytes 2-4/10"));
+        assert_eq!(body(response), "234");
+        assert_eq!(
+            body(site.respond(&request("/docs/a%20b.txt", &[("range", "bytes=-3")]))),
+            "789"
+        );
+        let stale = [("range", "bytes=2-4"), ("if-range", "\"old\"")];
+        assert_eq!(
+            site.respond(&request("/docs/a%20b.txt", &stale)).status,
+            200
+        );
+        let response = site.respond(&request("/docs/a%20b.txt", &[("range", "bytes=10-")]));
+        assert_eq!(response.status, 416);
+
+        assert_eq!(parse_range("bytes=0-99", 10), Some(Ok((0, 9))));
+        assert_eq!(parse_range("bytes=0-1,4-5", 10), None);
+        assert_eq!(parse_range("bytes=-0", 10), Some(Err(())));
+    }
+
+    #[test]
+    fn test_spa_fallback() {
+        let (app_dir, site) = site(true);
+        assert_eq!(
+            body(site.respond(&request("/settings/profile", &[]))),
+            "<h1>home</h1>"
+        );
+        assert_eq!(
+            body(site.respond(&request("/config", &[]))),
+            "<h1>home</h1>"
+        );
+        assert_eq!(site.respond(&request("/missing.js", &[])).status, 404);
+
+        // The fallback obeys the same containment as any other request.
+        fs::remove_file(site.root.join("index.html")).unwrap();
+        std::os::unix::fs::symlink(
+            app_dir.path().join("smallweb.json"),
+            site.root.join("index.html"),
+        )
+        .unwrap();
+        assert_eq!(site.respond(&request("/settings/profile", &[])).status, 404);
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let (_app_dir, site) = site(false);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let activity = Arc::new(Activity::new());
+        for _ in 0..MAX_CONNECTIONS {
+            activity.opened();
+        }
+        spawn_server(listener, site, Arc::clone(&activity));
+
+        let mut response = String::new();
+        TcpStream::connect(address)
+            .unwrap()
+            .read_to_string(&mut response)
+            .unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+            "{}",
+            response
+        );
+
+        activity.closed();
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn test_read_request() {
+        let raw =
+            "\r\nGET /a HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\nHEAD / HTTP/1.0\r\n\r\n";
+        let mut reader = BufReader::new(raw.as_bytes());
+        let first = read_request(&mut reader).unwrap().unwrap();
+        assert_eq!(
+            (first.method.as_str(), first.target.as_str()),
+            ("GET", "/a")
+        );
+        assert_eq!(first.header("host"), Some("x"));
+        assert!(!first.keep_alive);
+        let second = read_request(&mut reader).unwrap().unwrap();
+        assert!(!second.keep_alive);
+        assert!(read_request(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_static_config() {
+        let site: StaticSite = serde_json::from_str(r#""public/""#).unwrap();
+        assert_eq!(site.dir(), Path::new("public/"));
+        let site: StaticSite = serde_json::from_str(r#"{"dir": "dist", "spa": true}"#).unwrap();
+        assert!(site.spa());
+        assert!(
+            serde_json::from_str::<StaticSite>(r#"{"dir": "dist", "fallback": true}"#).is_err()
+        );
+
+        let app_dir = tempdir().unwrap();
+        assert!(Site::resolve(&StaticSite::Dir("..".into()), app_dir.path()).is_err());
+        assert_eq!(
+            StaticSite::default_for(app_dir.path()),
+            StaticSite::Dir(".".into())
+        );
+    }
+}
@@ -0,0 +1,408 @@
+use crate::applog::RotatingLog;
+use crate::linux;
+use crate::logging::{self, timestamp};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Size at which the history file is rotated to `history.jsonl.1`, so it
+/// holds at most twice this much.
+const MAX_BYTES: u64 = 10 * 1024 * 1024;
+
+config_struct! {
+    /// The append-only record of every launch decision, read back by
+    /// `not-deno history`.
+    #[derive(Default)]
+    pub struct HistorySettings {
+        /// JSON-lines file to append to. Defaults to
+        /// `.local/state/smallweb-adapter/history.jsonl` in the home dir
+        /// from the password database, so that launches, whose environment
+        /// Smallweb strips, and `not-deno history` agree on it. Users
+        /// without one get the adapter's private dir.
+        pub path: Option<PathBuf>,
+        /// Set to false to stop recording. Defaults to true.
+        pub enabled: Option<bool>,
+    }
+}
+
+impl HistorySettings {
+    pub fn path(&self) -> io::Result<PathBuf> {
+        if let Some(path) = &self.path {
+            return Ok(path.clone());
+        }
+        match linux::passwd_home() {
+            Some(home) => Ok(home.join(".local/state/smallweb-adapter/history.jsonl")),
+            None => Ok(linux::user_private_dir()?.join("history.jsonl")),
+        }
+    }
+}
+
+/// One line of the history file.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Record {
+    pub timestamp: String,
+    pub invocation: String,
+    pub app: Option<String>,
+    pub app_dir: Option<PathBuf>,
+    pub action: String,
+    /// Deno permission flags Smallweb passed, e.g. `--allow-net`.
+    pub permissions: Vec<String>,
+    /// Sandbox bind mounts as `ro:/path` or `rw:/path`.
+    pub mounts: Vec<String>,
+    /// The adapter's exit code. Missing when deno was exec'd in our place.
+    pub exit_code: Option<i32>,
+    /// The signal that killed the app, if it was killed by one.
+    pub signal: Option<i32>,
+    pub duration_ms: u64,
+}
+
+/// An invocation being recorded, written out by `finish`.
+pub struct Invocation {
+    path: Option<PathBuf>,
+    record: Record,
+    start: Instant,
+}
+
+impl Invocation {
+    pub fn start(
+        settings: &HistorySettings,
+        app: Option<String>,
+        app_dir: Option<PathBuf>,
+        action: &str,
+        args: &[String],
+    ) -> Invocation {
+        let path = match settings.enabled.unwrap_or(true).then(|| settings.path()) {
+            Some(Err(e)) => {
+                debug_log!("not recording history: {}", e);
+                None
+            }
+            path => path.and_then(Result::ok),
+        };
+        Invocation {
+            path,
+            record: Record {
+                timestamp: timestamp(),
+                invocation: logging::invocation_id(),
+                app,
+                app_dir,
+                action: action.to_string(),
+                permissions: permission_flags(args),
+                mounts: Vec::new(),
+                exit_code: None,
+                signal: None,
+                duration_ms: 0,
+            },
+            start: Instant::now(),
+        }
+    }
+
+    pub fn set_mounts(&mut self, bwrap_args: &[String]) {
+        self.record.mounts = mounts(bwrap_args);
+    }
+
+    pub fn set_signal(&mut self, signal: Option<i32>) {
+        self.record.signal = signal;
+    }
+
+    /// Appends the record with the given exit code. Failures are logged,
+    /// never fatal.
+    pub fn finish(mut self, exit_code: Option<i32>) {
+        let Some(path) = self.path.take() else {
+            return;
+        };
+        self.record.exit_code = exit_code;
+        self.record.duration_ms = self.start.elapsed().as_millis() as u64;
+        if let Err(e) = append(&path, &self.record) {
+            debug_log!("failed to write history {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// The permission flags in `args`, masked like log lines: `--allow-env=`
+/// names which secrets the app reads.
+fn permission_flags(args: &[String]) -> Vec<String> {
+    args.iter()
+        .filter(|arg| arg.starts_with("--allow-") || arg.starts_with("--deny-") || *arg == "-A")
+        .map(|arg| logging::redact(arg))
+        .collect()
+}
+
+fn mounts(bwrap_args: &[String]) -> Vec<String> {
+    bwrap_args
+        .windows(2)
+        .filter_map(|pair| match pair[0].as_str() {
+            "--ro-bind" => Some(format!("ro:{}", pair[1])),
+            "--bind" => Some(format!("rw:{}", pair[1])),
+            _ => None,
+        })
+        .collect()
+}
+
+fn append(path: &Path, record: &Record) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(parent)?;
+    }
+    let mut line = serde_json::to_string(record).map_err(io::Error::other)?;
+    line.push('\n');
+    // Someone who planted the file, or a symlink in its place, could read
+    // or rewrite what every app was allowed to do. One write per record, so
+    // concurrent launches don't interleave lines.
+    RotatingLog::open_private(path.to_path_buf(), MAX_BYTES, 1)?.append(line.as_bytes())
+}
+
+/// Reads the history file and its rotated predecessor, oldest first,
+/// skipping lines that don't parse.
+pub fn read(path: &Path) -> io::Result<Vec<Record>> {
+    let rotated = fs::read_to_string(format!("{}.1", path.display())).unwrap_or_default();
+    let content = rotated + &fs::read_to_string(path)?;
+    Ok(content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+/// Keeps the last `limit` records of `app`, matched by name or app dir.
+pub fn filter(records: Vec<Record>, app: Option<&str>, limit: usize) -> Vec<Record> {
+    let mut records: Vec<Record> = records
+        .into_iter()
+        .filter(|record| {
+            app.is_none_or(|app| {
+                record.app.as_deref() == Some(app)
+                    || record.app_dir.as_deref() == Some(Path::new(app))
+            })
+        })
+        .collect();
+    let skip = records.len().saturating_sub(limit);
+    records.drain(..skip);
+    records
+}
+
+/// Formats a record as one line of `not-deno history` output.
+pub fn format_record(record: &Record) -> String {
+    let status = match (record.exit_code, record.signal) {
+        (_, Some(signal)) => format!("sig{}", signal),
+        (Some(code), None) => code.to_string(),
+        (None, None) => "-".to_string(),
+    };
+    let mut line = format!(
+        "{} {:<20} {:<14} {:>5} {:>8}ms {}",
+        record.timestamp,
+        record.app.as_deref().unwrap_or("-"),
+        record.action,
+        status,
+        record.duration_ms,
+        record.permissions.join(" "),
+    );
+    if !record.mounts.is_empty() {
+        line.push_str(&format!(" [{}]", record.mounts.join(" ")));
+    }
+    line.trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process::{Command, Stdio};
+    use tempfile::tempdir;
+
+    /// Set in the copy of the test binary that prints the default path.
+    const PRINT_PATH_ENV: &str = "SMALLWEB_ADAPTER_TEST_PRINT_HISTORY_PATH";
+
+    fn to_string_vec(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_record_round_trip() {
+        let temp_dir = tempdir().unwrap();
+        let settings = HistorySettings {
+            path: Some(temp_dir.path().join("state/history.jsonl")),
+            enabled: None,
+        };
+        let args = to_string_vec(&[
+            "deno",
+            "run",
+            "--allow-net",
+            "--allow-read=/data",
+            "--allow-env=STRIPE_KEY",
+            "-",
+            "{}",
+        ]);
+        let path = settings.path().unwrap();
+
+        let mut invocation = Invocation::start(
+            &settings,
+            Some("blog".into()),
+            Some("/apps/blog".into()),
+            "exec",
+            &args,
+        );
+        invocation.set_mounts(&to_string_vec(&[
+            "--die-with-parent",
+            "--ro-bind",
+            "/usr",
+            "/usr",
+            "--bind",
+            "/data",
+            "/data",
+        ]));
+        invocation.set_signal(Some(9));
+        invocation.finish(Some(1));
+        Invocation::start(&settings, Some("docs".into()), None, "exec_deno", &args).finish(None);
+        std::fs::write(
+            &path,
+            std::fs::read_to_string(&path).unwrap() + "not json\n",
+        )
+        .unwrap();
+
+        let records = read(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            records[0].permissions,
+            [
+                "--allow-net",
+                "--allow-read=/data",
+                "--allow-env=[REDACTED]"
+            ]
+        );
+        assert!(!std::fs::read_to_string(&path)
+            .unwrap()
+            .contains("STRIPE_KEY"));
+        assert_eq!(records[0].mounts, ["ro:/usr", "rw:/data"]);
+        assert_eq!(records[0].exit_code, Some(1));
+        assert_eq!(records[1].action, "exec_deno");
+        assert_eq!(records[1].exit_code, None);
+        assert!(
+            format_record(&records[0]).contains(" exec ")
+                && format_record(&records[0]).contains("sig9")
+        );
+
+        let blog = filter(records.clone(), Some("/apps/blog"), 10);
+        assert_eq!(blog.len(), 1);
+        assert_eq!(blog[0].app.as_deref(), Some("blog"));
+        let last = filter(records, None, 1);
+        assert_eq!(last[0].app.as_deref(), Some("docs"));
+    }
+
+    #[test]
+    fn test_disabled() {
+        let temp_dir = tempdir().unwrap();
+        let settings = HistorySettings {
+            path: Some(temp_dir.path().join("history.jsonl")),
+            enabled: Some(false),
+        };
+        Invocation::start(&settings, None, None, "abort", &[]).finish(Some(1));
+        assert!(!settings.path().unwrap().exists());
+    }
+
+    #[test]
+    fn test_rotation_keeps_previous_generation() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("history.jsonl");
+        let record = |action: &str| Record {
+            timestamp: timestamp(),
+            invocation: "0".to_string(),
+            app: None,
+            app_dir: None,
+            action: action.to_string(),
+            permissions: Vec::new(),
+            mounts: Vec::new(),
+            exit_code: None,
+            signal: None,
+            duration_ms: 0,
+        };
+        std::fs::write(&path, " ".repeat(MAX_BYTES as usize) + "\n").unwrap();
+        append(&path, &record("exec")).unwrap();
+        append(&path, &record("abort")).unwrap();
+
+        assert!(std::fs::metadata(&path).unwrap().len() < 1024);
+        let actions: Vec<String> = read(&path).unwrap().into_iter().map(|r| r.action).collect();
+        assert_eq!(actions, ["exec", "abort"]);
+    }
+
+    #[test]
+    fn test_refuses_symlinked_file() {
+        let temp_dir = tempdir().unwrap();
+        let target = temp_dir.path().join("elsewhere");
+        std::fs::write(&target, "").unwrap();
+        let path = temp_dir.path().join("history.jsonl");
+        std::os::unix::fs::symlink(&target, &path).unwrap();
+        let settings = HistorySettings {
+            path: Some(path),
+            enabled: None,
+        };
+        Invocation::start(&settings, None, None, "abort", &[]).finish(Some(1));
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "");
+
+        // Nor is a file created where a dangling symlink points.
+        let dangling = temp_dir.path().join("dangling.jsonl");
+        std::os::unix::fs::symlink(temp_dir.path().join("planted"), &dangling).unwrap();
+        let settings = HistorySettings {
+            path: Some(dangling),
+            enabled: None,
+        };
+        Invocation::start(&settings, None, None, "abort", &[]).finish(Some(1));
+        assert!(!temp_dir.path().join("planted").exists());
+    }
+
+    #[test]
+    fn test_private_modes() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("state/smallweb-adapter/history.jsonl");
+        let settings = HistorySettings {
+            path: Some(path.clone()),
+            enabled: None,
+        };
+        Invocation::start(&settings, None, None, "abort", &[]).finish(Some(1));
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(mode(path.parent().unwrap()), 0o700);
+        assert_eq!(mode(&temp_dir.path().join("state")), 0o700);
+    }
+
+    #[test]
+    fn test_default_path_ignores_environment() {
+        if env::var_os(PRINT_PATH_ENV).is_some() {
+            println!(
+                "history path: {}",
+                HistorySettings::default().path().unwrap().display()
+            );
+            return;
+        }
+        // A launch sees an empty environment, `not-deno history` the
+        // user's shell.
+        let default_path = |envs: &[(&str, &str)]| {
+            let output = Command::new(env::current_exe().unwrap())
+                .args([
+                    "history::tests::test_default_path_ignores_environment",
+                    "--exact",
+                    "--nocapture",
+                ])
+                .env_clear()
+                .env(PRINT_PATH_ENV, "1")
+                .envs(envs.iter().copied())
+                .stderr(Stdio::null())
+                .output()
+                .unwrap();
+            let stdout = String::from_utf8(output.stdout).unwrap();
+            let line = stdout.lines().find_map(|l| l.split_once("history path: "));
+            line.unwrap().1.to_string()
+        };
+        let launch = default_path(&[]);
+        let interactive = default_path(&[
+            ("HOME", "/home/elsewhere"),
+            ("XDG_STATE_HOME", "/home/elsewhere/.state"),
+            ("XDG_RUNTIME_DIR", "/run/user/elsewhere"),
+        ]);
+        assert_eq!(launch, interactive);
+        assert!(launch.ends_with("/history.jsonl"));
+    }
+}
@@ -0,0 +1,164 @@
+use std::fmt;
+
+/// A JSONC syntax error with a 1-based line and column, mirroring the
+/// positions serde_json reports.
+#[derive(Debug, PartialEq)]
+pub struct JsoncError {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for JsoncError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at line {} column {}",
+            self.message, self.line, self.column
+        )
+    }
+}
+
+/// Turns JSONC into plain JSON by blanking out `//` and `/* */` comments and
+/// trailing commas. Everything removed is replaced with spaces (newlines are
+/// kept), so byte offsets, and therefore serde_json's line/column positions,
+/// still point into the original text.
+pub fn strip(input: &str) -> Result<String, JsoncError> {
+    let bytes = input.as_bytes();
+    let mut out = bytes.to_vec();
+    // Offset of the last comma seen outside a string, cleared by any other
+    // significant character.
+    let mut pending_comma: Option<usize> = None;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' => {
+                pending_comma = None;
+                i += 1;
+                while i < bytes.len() && bytes[i] != b'"' {
+                    if bytes[i] == b'\\' {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+                i += 1;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'/') => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    out[i] = b' ';
+                    i += 1;
+                }
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                let start = i;
+                i += 2;
+                loop {
+                    if i + 1 >= bytes.len() {
+                        return Err(error_at(input, start, "unterminated block comment"));
+                    }
+                    if bytes[i] == b'*' && bytes[i + 1] == b'/' {
+                        break;
+                    }
+                    i += 1;
+                }
+                for byte in &mut out[start..i + 2] {
+                    if *byte != b'\n' {
+                        *byte = b' ';
+                    }
+                }
+                i += 2;
+            }
+            b',' => {
+                pending_comma = Some(i);
+                i += 1;
+            }
+            b'}' | b']' => {
+                if let Some(comma) = pending_comma.take() {
+                    out[comma] = b' ';
+                }
+                i += 1;
+            }
+            byte if byte.is_ascii_whitespace() => i += 1,
+            _ => {
+                pending_comma = None;
+                i += 1;
+            }
+        }
+    }
+
+    // Only ASCII bytes outside of strings were replaced with ASCII spaces.
+    Ok(String::from_utf8(out).expect("stripping comments keeps the input valid UTF-8"))
+}
+
+/// Finds the 1-based line and column of the first occurrence of `"key":` in
+/// `text`, for pointing config errors at the offending key.
+pub fn key_position(text: &str, key: &str) -> Option<(usize, usize)> {
+    let needle = serde_json::to_string(key).ok()?;
+    let mut search_from = 0;
+    while let Some(found) = text[search_from..].find(&needle) {
+        let start = search_from + found;
+        let rest = text[start + needle.len()..].trim_start();
+        if rest.starts_with(':') {
+            return Some(line_column(text, start));
+        }
+        search_from = start + needle.len();
+    }
+    None
+}
+
+fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+    (line, column)
+}
+
+fn error_at(text: &str, offset: usize, message: &str) -> JsoncError {
+    let (line, column) = line_column(text, offset);
+    JsoncError {
+        message: message.to_string(),
+        line,
+        column,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_comments_and_trailing_commas() {
+        let input = r#"{
+  // the app server
+  "exec": "./server --port $PORT", /* inline */
+  "crons": [1, 2,],
+}"#;
+        let value: serde_json::Value = serde_json::from_str(&strip(input).unwrap()).unwrap();
+        assert_eq!(value["exec"], "./server --port $PORT");
+        assert_eq!(value["crons"], serde_json::json!([1, 2]));
+    }
+
+    #[test]
+    fn test_comment_markers_inside_strings() {
+        let input = r#"{"exec": "curl http://example.com/* \"//\" */,}"}"#;
+        assert_eq!(strip(input).unwrap(), input);
+    }
+
+    #[test]
+    fn test_positions_are_preserved() {
+        let input = "{\n  /* comment */ \"exec\": 1,\n  oops\n}";
+        let err = serde_json::from_str::<serde_json::Value>(&strip(input).unwrap()).unwrap_err();
+        assert_eq!((err.line(), err.column()), (3, 3));
+        assert_eq!(key_position(input, "exec"), Some((2, 17)));
+    }
+
+    #[test]
+    fn test_unterminated_block_comment() {
+        let err = strip("{\n  /* never closed\n}").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unterminated block comment at line 2 column 3"
+        );
+    }
+}
@@ -1,13 +1,227 @@
-use std::os::unix::fs::MetadataExt;
-use std::path::Path;
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::ffi::{CStr, OsStr};
+use std::fs;
+use std::io;
+use std::os::fd::RawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Last termination signal received, or 0. Set from the signal handler.
+static PENDING_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// Signals forwarded to the sandbox instead of killing the adapter outright.
+pub const FORWARDED_SIGNALS: [i32; 3] = [libc::SIGTERM, libc::SIGINT, libc::SIGHUP];
+
+extern "C" fn record_signal(signal: libc::c_int) {
+    PENDING_SIGNAL.store(signal, Ordering::SeqCst);
+}
+
+/// Replaces the default "terminate" action of `FORWARDED_SIGNALS` with a
+/// handler that only records the signal, to be picked up by
+/// `take_pending_signal`.
+pub fn install_signal_handlers() {
+    for signal in FORWARDED_SIGNALS {
+        // SAFETY: the handler only performs an atomic store, which is
+        // async-signal-safe, and the sigaction struct is fully initialized.
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = record_signal as *const () as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+                warn_log!("failed to install handler for signal {}", signal);
+            }
+        }
+    }
+}
+
+/// Returns the signal received since the last call, if any.
+pub fn take_pending_signal() -> Option<i32> {
+    match PENDING_SIGNAL.swap(0, Ordering::SeqCst) {
+        0 => None,
+        signal => Some(signal),
+    }
+}
+
+/// Parent PID and process group of `pid`, from `/proc/<pid>/stat`.
+fn stat_ppid_pgrp(pid: u32) -> Option<(u32, u32)> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name is in parentheses and may itself contain spaces or
+    // parentheses, so parse from the last ')'.
+    let mut fields = stat[stat.rfind(')')? + 1..].split_whitespace();
+    let _state = fields.next()?;
+    let ppid = fields.next()?.parse().ok()?;
+    let pgrp = fields.next()?.parse().ok()?;
+    Some((ppid, pgrp))
+}
+
+/// All live descendants of `pid` with their process groups, found by walking
+/// the parent links in `/proc`.
+pub fn descendants(pid: u32) -> Vec<(u32, u32)> {
+    let mut children: HashMap<u32, Vec<(u32, u32)>> = HashMap::new();
+    if let Ok(entries) = fs::read_dir("/proc") {
+        for entry in entries.flatten() {
+            let Some(child) = entry.file_name().to_str().and_then(|n| n.parse().ok()) else {
+                continue;
+            };
+            if let Some((ppid, pgrp)) = stat_ppid_pgrp(child) {
+                children.entry(ppid).or_default().push((child, pgrp));
+            }
+        }
+    }
+
+    let mut result = Vec::new();
+    let mut queue = vec![pid];
+    while let Some(parent) = queue.pop() {
+        for &(child, pgrp) in children.get(&parent).into_iter().flatten() {
+            result.push((child, pgrp));
+            queue.push(child);
+        }
+    }
+    result
+}
+
+/// Sends `signal` to the process groups inside the sandbox started as `pid`.
+///
+/// bwrap's `--new-session` puts the sandboxed command in a session of its
+/// own, so its process group differs from bwrap's. bwrap itself is left
+/// alone: if it died first, `--die-with-parent` would SIGKILL the app before
+/// it could shut down. When no such group exists yet (or the sandbox runs
+/// without a new session) the group `pid` leads, or `pid` alone, is
+/// signalled instead.
+pub fn signal_sandbox(pid: u32, signal: i32) {
+    let own_group = stat_ppid_pgrp(pid).map(|(_, pgrp)| pgrp);
+    let groups: BTreeSet<u32> = descendants(pid)
+        .into_iter()
+        .map(|(_, pgrp)| pgrp)
+        .filter(|pgrp| Some(*pgrp) != own_group)
+        .collect();
+
+    if groups.is_empty() {
+        debug_log!("no sandbox session under {}, signalling it directly", pid);
+        // SAFETY: kill and killpg have no memory-safety preconditions.
+        unsafe {
+            if own_group == Some(pid) {
+                libc::killpg(pid as libc::pid_t, signal);
+            } else {
+                libc::kill(pid as libc::pid_t, signal);
+            }
+        }
+        return;
+    }
+    for pgrp in groups {
+        debug_log!("sending signal {} to process group {}", signal, pgrp);
+        // SAFETY: killpg has no memory-safety preconditions.
+        unsafe { libc::killpg(pgrp as libc::pid_t, signal) };
+    }
+}
+
+/// Makes orphans anywhere below us reparent to us instead of to init, so a
+/// process that daemonizes inside the sandbox stays one of our descendants.
+pub fn become_subreaper() {
+    // SAFETY: prctl with PR_SET_CHILD_SUBREAPER only sets a process flag.
+    if unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) } != 0 {
+        warn_log!(
+            "failed to become child subreaper: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+/// Whether `pid` exists and isn't a zombie.
+pub fn is_alive(pid: u32) -> bool {
+    fs::read_to_string(format!("/proc/{}/stat", pid))
+        .ok()
+        .and_then(|stat| {
+            stat.rfind(')')
+                .and_then(|end| stat[end + 1..].split_whitespace().next().map(String::from))
+        })
+        .is_some_and(|state| state != "Z" && state != "X")
+}
+
+/// Whether `pid` is the init of a PID namespace nested below ours, as bwrap
+/// starts with `--unshare-pid`. Killing it kills everything in the namespace.
+fn is_pid_namespace_init(pid: u32) -> bool {
+    let Ok(status) = fs::read_to_string(format!("/proc/{}/status", pid)) else {
+        return false;
+    };
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("NSpid:"))
+        .map(|pids| pids.split_whitespace().collect::<Vec<_>>())
+        .is_some_and(|pids| pids.len() > 1 && pids.last() == Some(&"1"))
+}
+
+/// SIGKILLs every live descendant of `root` (and `root` itself when
+/// `include_root`), repeating until none is left so that processes forked in
+/// the meantime are caught as well. PID namespace inits go first, taking
+/// their whole namespace down with them. Gives up after `timeout`; returns
+/// the number of processes killed.
+pub fn kill_tree(root: u32, include_root: bool, timeout: Duration) -> usize {
+    let deadline = Instant::now() + timeout;
+    let mut killed = BTreeSet::new();
+    loop {
+        let mut tree: Vec<u32> = descendants(root)
+            .into_iter()
+            .map(|(pid, _)| pid)
+            .filter(|pid| is_alive(*pid))
+            .collect();
+        if include_root && is_alive(root) {
+            tree.push(root);
+        }
+        if tree.is_empty() {
+            break;
+        }
+        if Instant::now() >= deadline {
+            warn_log!("processes {:?} survived SIGKILL", tree);
+            break;
+        }
+        tree.sort_by_key(|pid| !is_pid_namespace_init(*pid));
+        for pid in tree {
+            trace_log!("killing sandbox process {}", pid);
+            // SAFETY: kill has no memory-safety preconditions.
+            unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) };
+            killed.insert(pid);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    killed.len()
+}
+
+/// Number of OOM kills so far in our cgroup, from `memory.events` (cgroup
+/// v2 only). The sandbox shares the adapter's cgroup.
+pub fn oom_kill_count() -> Option<u64> {
+    let cgroups = fs::read_to_string("/proc/self/cgroup").ok()?;
+    let path = cgroups.lines().find_map(|line| line.strip_prefix("0::"))?;
+    let events = fs::read_to_string(format!("/sys/fs/cgroup{}/memory.events", path)).ok()?;
+    events
+        .lines()
+        .find_map(|line| line.strip_prefix("oom_kill "))
+        .and_then(|count| count.trim().parse().ok())
+}
+
+/// Reaps any of our children that have exited, such as orphans of the
+/// sandbox reparented to us. Only call once the sandbox `Child` was waited.
+pub fn reap_children() {
+    // SAFETY: waitpid with a null status pointer and WNOHANG never blocks and
+    // writes no memory.
+    while unsafe { libc::waitpid(-1, std::ptr::null_mut(), libc::WNOHANG) } > 0 {}
+}
 
 pub fn is_port_listening(port: u16) -> bool {
-    debug_log!("[netstat] checking for port {}", port);
+    trace_log!("[netstat] checking for port {}", port);
     let output = match Command::new("netstat").arg("-tln").output() {
         Ok(output) => output,
         Err(e) => {
-            debug_log!("[netstat] failed to run: {}", e);
+            trace_log!("[netstat] failed to run: {}", e);
             // netstat might not be installed, or we are on a system that doesn't have it.
             // We can't check, so we'll have to assume it's not listening, or handle this case diff
             // For now, let's assume it's not listening if we can't run netstat.
@@ -16,23 +230,180 @@ pub fn is_port_listening(port: u16) -> bool {
     };
 
     if !output.status.success() {
-        debug_log!("[netstat] failed with status: {}", output.status);
+        trace_log!("[netstat] failed with status: {}", output.status);
         return false;
     }
 
     let stdout = String::from_utf8_lossy(&output.stdout);
     for line in stdout.lines() {
         if line.contains(&format!(":{}", port)) {
-            debug_log!("[netstat] found port {} in use", port);
+            trace_log!("[netstat] found port {} in use", port);
             return true;
         }
     }
 
-    debug_log!("[netstat] port {} not in use", port);
+    trace_log!("[netstat] port {} not in use", port);
     false
 }
 
-fn bind_mount(path: &str, rw: bool) -> Option<[String; 3]> {
+/// Whether the sandbox started as `pid` listens on TCP `port` in its own
+/// network namespace, as seen from any process inside it.
+pub fn is_port_listening_in_sandbox(pid: u32, port: u16) -> bool {
+    let Some(&(inner, _)) = descendants(pid).first() else {
+        return false;
+    };
+    let listening = any_tcp_socket(&inner.to_string(), |local, state| {
+        state == TCP_LISTEN && local == port
+    });
+    trace_log!(
+        "[proc] port {} listening in sandbox {}: {}",
+        port,
+        pid,
+        listening
+    );
+    listening
+}
+
+/// Whether a TCP connection accepted on our `port` exists in our network
+/// namespace, counting those in TIME_WAIT after a recent close. Outgoing
+/// connections to some other host's `port` don't count.
+pub fn has_connections(port: u16) -> bool {
+    any_tcp_socket("self", |local, state| state != TCP_LISTEN && local == port)
+}
+
+/// State of a listening socket in `/proc/net/tcp`.
+const TCP_LISTEN: &str = "0A";
+
+/// Whether `matches` holds for any IPv4 or IPv6 socket in the network
+/// namespace of `/proc/<pid>`.
+fn any_tcp_socket(pid: &str, matches: impl Fn(u16, &str) -> bool) -> bool {
+    ["tcp", "tcp6"].iter().any(|table| {
+        fs::read_to_string(format!("/proc/{}/net/{}", pid, table))
+            .is_ok_and(|content| tcp_sockets(&content).any(|(local, state)| matches(local, state)))
+    })
+}
+
+/// Local port and state of each socket in a `/proc/net/tcp`-style table.
+fn tcp_sockets(table: &str) -> impl Iterator<Item = (u16, &str)> + '_ {
+    table.lines().skip(1).filter_map(|line| {
+        let mut fields = line.split_whitespace();
+        let local_address = fields.nth(1)?;
+        let local = u16::from_str_radix(local_address.rsplit_once(':')?.1, 16).ok()?;
+        Some((local, fields.nth(1)?))
+    })
+}
+
+/// Whether something accepts connections on the Unix socket at `path`.
+pub fn is_socket_listening(path: &Path) -> bool {
+    let is_socket = fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket());
+    let listening = is_socket && UnixStream::connect(path).is_ok();
+    trace_log!("[socket] {} listening: {}", path.display(), listening);
+    listening
+}
+
+/// The first fd passed under systemd's socket activation protocol.
+pub const LISTEN_FDS_START: RawFd = 3;
+
+/// Hands `fd` to the processes spawned by `command` as fd 3, with
+/// `LISTEN_FDS=1`. `LISTEN_PID` can only be set inside the sandbox, where
+/// PIDs are namespaced.
+pub fn pass_listen_fd(command: &mut Command, fd: RawFd) {
+    command.env("LISTEN_FDS", "1");
+    // SAFETY: the closure only makes async-signal-safe fcntl/dup2 calls.
+    unsafe {
+        command.pre_exec(move || {
+            // dup2 clears close-on-exec on the new fd, but is a no-op when
+            // the fd is already in place.
+            let result = if fd == LISTEN_FDS_START {
+                libc::fcntl(fd, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(fd, LISTEN_FDS_START)
+            };
+            if result < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Marks `fd` close-on-exec, so that processes we spawn don't inherit it
+/// unless told otherwise.
+pub fn set_close_on_exec(fd: RawFd) -> io::Result<()> {
+    // SAFETY: fcntl has no memory-safety preconditions.
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A directory only we can access, for state other local users must not be
+/// able to plant or read: `$XDG_RUNTIME_DIR/smallweb-adapter`, or
+/// `smallweb-adapter-<uid>` in the temp dir when Smallweb stripped the
+/// environment. Created with mode 0700; refused when it already exists and
+/// belongs to someone else or is open to others.
+pub fn user_private_dir() -> io::Result<PathBuf> {
+    // SAFETY: getuid has no preconditions and can't fail.
+    let uid = unsafe { libc::getuid() };
+    let dir = match env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()) {
+        Some(runtime_dir) => PathBuf::from(runtime_dir).join("smallweb-adapter"),
+        None => env::temp_dir().join(format!("smallweb-adapter-{}", uid)),
+    };
+    match fs::DirBuilder::new().mode(0o700).create(&dir) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+        _ => {}
+    }
+    let metadata = fs::symlink_metadata(&dir)?;
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        let message = format!(
+            "{} is not a directory private to uid {}",
+            dir.display(),
+            uid
+        );
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, message));
+    }
+    Ok(dir)
+}
+
+/// Our home directory as recorded in the password database. Unlike `$HOME`
+/// it is the same whether or not Smallweb stripped the environment.
+pub fn passwd_home() -> Option<PathBuf> {
+    // SAFETY: getuid has no preconditions and can't fail.
+    let uid = unsafe { libc::getuid() };
+    // SAFETY: passwd is plain old data; getpwuid_r fills it in.
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 1024];
+    loop {
+        // SAFETY: all pointers are valid for the duration of the call and
+        // `buf.len()` is the size of `buf`.
+        let rc =
+            unsafe { libc::getpwuid_r(uid, &mut entry, buf.as_mut_ptr(), buf.len(), &mut result) };
+        if rc != libc::ERANGE || buf.len() >= 1 << 20 {
+            break;
+        }
+        buf.resize(buf.len() * 2, 0);
+    }
+    if result.is_null() || entry.pw_dir.is_null() {
+        return None;
+    }
+    // SAFETY: on success pw_dir points to a NUL-terminated string in `buf`.
+    let dir = unsafe { CStr::from_ptr(entry.pw_dir) };
+    let dir = PathBuf::from(OsStr::from_bytes(dir.to_bytes()));
+    (dir.is_absolute() && dir != Path::new("/")).then_some(dir)
+}
+
+/// Whether `path` is a regular file (not a symlink) of ours that nobody else
+/// can write to.
+pub fn is_own_private_file(path: &Path) -> bool {
+    // SAFETY: getuid has no preconditions and can't fail.
+    let uid = unsafe { libc::getuid() };
+    fs::symlink_metadata(path).is_ok_and(|m| m.is_file() && m.uid() == uid && m.mode() & 0o022 == 0)
+}
+
+/// Args mounting `path` at the same place in the sandbox, or `None` when it
+/// doesn't exist.
+pub fn bind_mount(path: &str, rw: bool) -> Option<[String; 3]> {
     if !Path::new(path).exists() {
         debug_log!("skipping bind mount for non-existent path: {}", path);
         return None;
@@ -43,11 +414,20 @@ fn bind_mount(path: &str, rw: bool) -> Option<[String; 3]> {
 
 pub fn deno_sandbox_to_bubblewrap_args(args: &[String], own_path: &Path) -> Vec<String> {
     let mut bwrap_args: Vec<String> = [
-        "--die-with-parent", "--unshare-pid", "--new-session",
-        "--proc", "/proc", "--dev", "/dev",
-        "--symlink", "usr/lib64", "/lib64",
+        "--die-with-parent",
+        "--unshare-pid",
+        "--new-session",
+        "--proc",
+        "/proc",
+        "--dev",
+        "/dev",
+        "--symlink",
+        "usr/lib64",
+        "/lib64",
     ]
-    .into_iter().map(String::from).collect();
+    .into_iter()
+    .map(String::from)
+    .collect();
 
     bwrap_args.extend(
         ["/bin", "/usr", "/lib"]
@@ -81,7 +461,8 @@ pub fn deno_sandbox_to_bubblewrap_args(args: &[String], own_path: &Path) -> Vec<
         true
     };
 
-    let read_args = args.iter()
+    let read_args = args
+        .iter()
         .filter_map(|arg| arg.strip_prefix("--allow-read="))
         .flat_map(|paths| paths.split(','))
         .filter(|path| !path.is_empty())
@@ -89,7 +470,8 @@ pub fn deno_sandbox_to_bubblewrap_args(args: &[String], own_path: &Path) -> Vec<
         .flat_map(|path| bind_mount(path, false))
         .flatten();
 
-    let write_args = args.iter()
+    let write_args = args
+        .iter()
         .filter_map(|arg| arg.strip_prefix("--allow-write="))
         .flat_map(|paths| paths.split(','))
         .filter(|path| !path.is_empty())
@@ -106,6 +488,8 @@ pub fn deno_sandbox_to_bubblewrap_args(args: &[String], own_path: &Path) -> Vec<
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::net::TcpListener;
+    use std::os::fd::AsRawFd;
     use std::path::Path;
     use tempfile::tempdir;
 
@@ -118,7 +502,9 @@ mod tests {
         let args = vec![];
         let bwrap_args = deno_sandbox_to_bubblewrap_args(&args, Path::new("/fake/deno"));
         assert!(bwrap_args.contains(&"--die-with-parent".to_string()));
-        assert!(bwrap_args.windows(3).any(|w| w == ["--ro-bind", "/bin", "/bin"]));
+        assert!(bwrap_args
+            .windows(3)
+            .any(|w| w == ["--ro-bind", "/bin", "/bin"]));
         assert!(!bwrap_args.contains(&"--share-net".to_string()));
     }
 
@@ -127,7 +513,9 @@ mod tests {
         let args = to_string_vec(&["--allow-net"]);
         let bwrap_args = deno_sandbox_to_bubblewrap_args(&args, Path::new("/fake/deno"));
         assert!(bwrap_args.contains(&"--share-net".to_string()));
-        assert!(bwrap_args.windows(3).any(|w| w == ["--ro-bind", "/etc/resolv.conf", "/etc/resolv.conf"]));
+        assert!(bwrap_args
+            .windows(3)
+            .any(|w| w == ["--ro-bind", "/etc/resolv.conf", "/etc/resolv.conf"]));
     }
 
     #[test]
@@ -147,7 +535,6 @@ mod tests {
             .any(|w| w == ["--ro-bind", "/tmp", "/tmp"]));
     }
 
-
     #[test]
     fn test_with_allow_write() {
         let temp_dir = tempdir().unwrap();
@@ -241,4 +628,121 @@ mod tests {
             .windows(3)
             .any(|w| w == ["--bind", writable_path_str, writable_path_str]));
     }
+
+    #[test]
+    fn test_signal_sandbox_reaches_new_session() {
+        // Mimic bwrap --new-session: the "app" runs in a session of its own
+        // underneath a parent that sits in a separate process group.
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg("setsid sleep 30 & wait")
+            .process_group(0)
+            .spawn()
+            .unwrap();
+
+        let start = Instant::now();
+        while !descendants(child.id())
+            .iter()
+            .any(|(_, pgrp)| *pgrp != child.id())
+        {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "app never started"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        signal_sandbox(child.id(), libc::SIGTERM);
+        let start = Instant::now();
+        while child.try_wait().unwrap().is_none() {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "sandbox ignored SIGTERM"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_tcp_sockets() {
+        let table = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0100007F:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 41203 1 0000000000000000 100 0 0 10 0
+   1: 0100007F:1F90 0100007F:C350 01 00000000:00000000 00:00000000 00000000  1000        0 41210 1 0000000000000000 20 4 30 10 -1
+   2: 0100007F:0277 0100007F:D431 06 00000000:00000000 03:00000E2B 00000000     0        0 0 3 0000000000000000
+";
+        assert_eq!(
+            tcp_sockets(table).collect::<Vec<_>>(),
+            [(8080, "0A"), (8080, "01"), (631, "06")]
+        );
+    }
+
+    #[test]
+    fn test_has_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _client = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let (_accepted, _) = listener.accept().unwrap();
+        assert!(has_connections(port));
+    }
+
+    #[test]
+    fn test_pass_listen_fd() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut command = Command::new("/bin/sh");
+        command.args(["-c", "[ -S /proc/self/fd/3 ] && echo \"$LISTEN_FDS\""]);
+        pass_listen_fd(&mut command, listener.as_raw_fd());
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "1\n");
+    }
+
+    #[test]
+    fn test_kill_tree_catches_daemons() {
+        // A stand-in for the adapter: a subreaper running an app that forks
+        // workers and a double-forked daemon in a session of its own.
+        let temp_dir = tempdir().unwrap();
+        let pids = temp_dir.path().join("pids");
+        let script = format!(
+            "sleep 300 & echo $! >> {0}
+             (setsid sh -c 'sleep 300 & echo $! >> {0}; wait' &) 
+             sh -c 'sleep 300 & echo $! >> {0}; wait' &
+             wait",
+            pids.display()
+        );
+        let mut root = Command::new("sh");
+        root.arg("-c").arg(script);
+        // SAFETY: prctl is async-signal-safe and touches no memory.
+        unsafe {
+            root.pre_exec(|| {
+                libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0);
+                Ok(())
+            });
+        }
+        let mut root = root.spawn().unwrap();
+
+        let start = Instant::now();
+        let daemons = loop {
+            let content = fs::read_to_string(&pids).unwrap_or_default();
+            let daemons: Vec<u32> = content
+                .lines()
+                .filter_map(|l| l.trim().parse().ok())
+                .collect();
+            if daemons.len() == 3 {
+                break daemons;
+            }
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "app never started"
+            );
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert!(daemons.iter().all(|pid| is_alive(*pid)));
+
+        let killed = kill_tree(root.id(), true, Duration::from_secs(5));
+        assert!(killed >= 4, "killed {}", killed);
+        root.wait().unwrap();
+        for pid in daemons {
+            assert!(!is_alive(pid), "process {} survived", pid);
+        }
+    }
 }
@@ -1,43 +1,760 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::hash_map::RandomState;
 use std::env;
 use std::fmt;
 use std::fs::{File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
 use std::io::Write;
 use std::path::Path;
+use std::str::FromStr;
 use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-static LOG_FILE: OnceLock<Mutex<Option<File>>> = OnceLock::new();
-
-// Lazily initializes a log file. To enable logging, the `logs` directory must exist
-// within the `SMALLWEB_APP_DIR`. If the log file can't be opened (e.g., due to
-// permissions or the directory not existing), logging will be silently disabled
-// for the remainder of the process's lifetime.
-fn get_log_file() -> &'static Mutex<Option<File>> {
-    LOG_FILE.get_or_init(|| {
-        let file = env::var("SMALLWEB_APP_DIR").ok().and_then(|app_dir| {
-            let path = Path::new(&app_dir)
-                .join("logs")
-                .join("smallweb-wrapper.log");
-            OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(path)
-                .ok()
-        });
-        Mutex::new(file)
-    })
+mod redact;
+
+pub use redact::Redactor;
+
+/// Env var selecting the log level, overriding `log.level` in the settings.
+pub const LEVEL_ENV: &str = "SMALLWEB_ADAPTER_LOG_LEVEL";
+/// Env var selecting the log destination, overriding `log.target`.
+pub const TARGET_ENV: &str = "SMALLWEB_ADAPTER_LOG_TARGET";
+/// Env var selecting the line format, overriding `log.format`.
+pub const FORMAT_ENV: &str = "SMALLWEB_ADAPTER_LOG_FORMAT";
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    const NAMES: [&'static str; 5] = ["error", "warn", "info", "debug", "trace"];
+
+    fn name(self) -> &'static str {
+        Level::NAMES[self as usize]
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(Value::String(s.to_lowercase())).map_err(|_| {
+            format!(
+                "invalid log level `{}`, expected one of: {}",
+                s,
+                Level::NAMES.join(", ")
+            )
+        })
+    }
+}
+
+impl crate::schema::JsonSchema for Level {
+    fn schema() -> Value {
+        json!({ "enum": Level::NAMES })
+    }
+}
+
+/// Where log lines go.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Target {
+    File,
+    Stderr,
+    Both,
+}
+
+impl Target {
+    const NAMES: [&'static str; 3] = ["file", "stderr", "both"];
+
+    fn to_file(self) -> bool {
+        self != Target::Stderr
+    }
+
+    fn to_stderr(self) -> bool {
+        self != Target::File
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(Value::String(s.to_lowercase())).map_err(|_| {
+            format!(
+                "invalid log target `{}`, expected one of: {}",
+                s,
+                Target::NAMES.join(", ")
+            )
+        })
+    }
+}
+
+impl crate::schema::JsonSchema for Target {
+    fn schema() -> Value {
+        json!({ "enum": Target::NAMES })
+    }
+}
+
+/// How each log line is written.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Human readable text with a prefix.
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+impl Format {
+    const NAMES: [&'static str; 2] = ["text", "json"];
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(Value::String(s.to_lowercase())).map_err(|_| {
+            format!(
+                "invalid log format `{}`, expected one of: {}",
+                s,
+                Format::NAMES.join(", ")
+            )
+        })
+    }
+}
+
+impl crate::schema::JsonSchema for Format {
+    fn schema() -> Value {
+        json!({ "enum": Format::NAMES })
+    }
+}
+
+/// Key moments of an invocation. In JSON mode these are logged with their
+/// fields under an `event` name; in text mode as a sentence.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// decide_action picked what to do with this invocation.
+    Decision { action: &'static str },
+    /// The build step succeeded, or was skipped as its inputs are unchanged.
+    Build { cached: bool, duration_ms: u64 },
+    /// The bwrap process was started.
+    SandboxSpawned { child_pid: u32 },
+    /// The app started listening and READY was printed.
+    Ready { port: u16, startup_ms: u64 },
+    /// The sandboxed process exited.
+    ChildExit {
+        code: Option<i32>,
+        signal: Option<i32>,
+        early: bool,
+        /// `exit`, `signal`, `oom` or `sandbox_*` for bwrap setup failures.
+        reason: &'static str,
+    },
+    /// The app didn't start listening in time.
+    Timeout { port: u16, timeout_secs: u64 },
+    /// The app exited and is restarted under its restart policy.
+    Restart { attempt: u32, delay_ms: u64 },
+    /// No connections for `idle_timeout`; the app is being stopped.
+    IdleShutdown { idle_secs: u64 },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Decision { action } => write!(f, "decided action: {}", action),
+            Event::Build { cached: true, .. } => {
+                write!(f, "build inputs unchanged, skipping build")
+            }
+            Event::Build { duration_ms, .. } => write!(f, "build finished in {}ms", duration_ms),
+            Event::SandboxSpawned { child_pid } => {
+                write!(f, "sandbox spawned with pid {}", child_pid)
+            }
+            Event::Ready { port, startup_ms } => {
+                write!(
+                    f,
+                    "port {} is listening after {}ms, READY",
+                    port, startup_ms
+                )
+            }
+            Event::ChildExit {
+                code,
+                signal,
+                early,
+                ..
+            } => {
+                let when = if *early {
+                    "before READY"
+                } else {
+                    "after READY"
+                };
+                match (code, signal) {
+                    (_, Some(signal)) => {
+                        write!(f, "child process killed by signal {} {}", signal, when)
+                    }
+                    (Some(code), None) => {
+                        write!(f, "child process exited with code {} {}", code, when)
+                    }
+                    (None, None) => write!(f, "child process exited {}", when),
+                }
+            }
+            Event::Timeout { port, timeout_secs } => {
+                write!(
+                    f,
+                    "timed out after {}s waiting for port {}",
+                    timeout_secs, port
+                )
+            }
+            Event::Restart { attempt, delay_ms } => {
+                write!(f, "restarting app in {}ms (attempt {})", delay_ms, attempt)
+            }
+            Event::IdleShutdown { idle_secs } => {
+                write!(f, "no connections for {}s, shutting down", idle_secs)
+            }
+        }
+    }
+}
+
+/// Fields attached to every JSON log line once they are known.
+#[derive(Debug, Default)]
+struct Context {
+    app: Option<String>,
+    port: Option<u16>,
+    action: Option<&'static str>,
+}
+
+config_struct! {
+    /// Adapter log settings. `$SMALLWEB_ADAPTER_LOG_LEVEL`,
+    /// `$SMALLWEB_ADAPTER_LOG_TARGET` and `$SMALLWEB_ADAPTER_LOG_FORMAT` take
+    /// precedence over these.
+    #[derive(Default)]
+    pub struct LogSettings {
+        /// Most verbose level written. Defaults to `info`.
+        pub level: Option<Level>,
+        /// `file` (`$SMALLWEB_APP_DIR/logs/smallweb-wrapper.log`), `stderr`
+        /// or `both`. Defaults to `file`.
+        pub target: Option<Target>,
+        /// `text` or `json` (one object per line). Defaults to `text`.
+        pub format: Option<Format>,
+        /// Extra key names whose values are masked, on top of the built-in
+        /// ones (`token`, `secret`, `password`, `auth` as a word, ...).
+        /// Matched case-insensitively anywhere in the key.
+        pub redact_keys: Option<Vec<String>>,
+        /// Patterns masked wherever they appear in a log line. `*` matches
+        /// a run of anything but whitespace, commas, semicolons and quotes,
+        /// e.g. `sk_live_*`.
+        pub redact: Option<Vec<String>>,
+    }
+}
+
+pub struct Logger {
+    level: Level,
+    target: Target,
+    format: Format,
+    file: Option<File>,
+    invocation_id: String,
+    context: Context,
+    redactor: Redactor,
+}
+
+impl Logger {
+    fn new(level: Level, target: Target, file: Option<File>) -> Logger {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u32(std::process::id());
+        Logger {
+            level,
+            target,
+            format: Format::Text,
+            file,
+            invocation_id: format!("{:08x}", hasher.finish() as u32),
+            context: Context::default(),
+            redactor: Redactor::default(),
+        }
+    }
+
+    /// The logger as configured by the environment alone.
+    fn from_env() -> Logger {
+        let level = env_override::<Level>(LEVEL_ENV).unwrap_or(Level::Info);
+        let target = env_override::<Target>(TARGET_ENV).unwrap_or(Target::File);
+        let mut logger = Logger::new(
+            level,
+            target,
+            target.to_file().then(open_log_file).flatten(),
+        );
+        logger.format = env_override::<Format>(FORMAT_ENV).unwrap_or(Format::Text);
+        logger
+    }
+
+    fn apply(&mut self, settings: &LogSettings) {
+        if env_override::<Level>(LEVEL_ENV).is_none() {
+            if let Some(level) = settings.level {
+                self.level = level;
+            }
+        }
+        if env_override::<Target>(TARGET_ENV).is_none() {
+            if let Some(target) = settings.target {
+                if target.to_file() && self.file.is_none() {
+                    self.file = open_log_file();
+                }
+                self.target = target;
+            }
+        }
+        if env_override::<Format>(FORMAT_ENV).is_none() {
+            if let Some(format) = settings.format {
+                self.format = format;
+            }
+        }
+        self.redactor = Redactor::new(
+            settings.redact_keys.as_deref().unwrap_or_default(),
+            settings.redact.as_deref().unwrap_or_default(),
+        );
+    }
+
+    fn enabled(&self, level: Level) -> bool {
+        level <= self.level
+    }
+
+    fn format_line(&self, level: Level, message: &str, event: Option<&Event>) -> String {
+        match self.format {
+            Format::Text => format!(
+                "{} {:<5} [{}] [{}] {}",
+                timestamp(),
+                level.name().to_uppercase(),
+                std::process::id(),
+                self.invocation_id,
+                message
+            ),
+            Format::Json => {
+                let mut object = Map::new();
+                object.insert("timestamp".into(), timestamp().into());
+                object.insert("level".into(), level.name().into());
+                object.insert("pid".into(), std::process::id().into());
+                object.insert("invocation".into(), self.invocation_id.clone().into());
+                object.insert("app".into(), json!(self.context.app));
+                object.insert("port".into(), json!(self.context.port));
+                object.insert("action".into(), json!(self.context.action));
+                if let Some(Value::Object(fields)) =
+                    event.and_then(|e| serde_json::to_value(e).ok())
+                {
+                    object.extend(fields);
+                }
+                object.insert("message".into(), message.into());
+                Value::Object(object).to_string()
+            }
+        }
+    }
+
+    fn log(&mut self, level: Level, args: fmt::Arguments) {
+        self.write(level, &args.to_string(), None);
+    }
+
+    fn log_event(&mut self, level: Level, event: &Event) {
+        if let Event::Decision { action } = event {
+            self.context.action = Some(action);
+        }
+        self.write(level, &event.to_string(), Some(event));
+    }
+
+    fn write(&mut self, level: Level, message: &str, event: Option<&Event>) {
+        if !self.enabled(level) {
+            return;
+        }
+        // Everything is redacted before it is formatted, so secrets reach
+        // neither the file nor stderr.
+        let message = self.redactor.redact(message);
+        let line = self.format_line(level, &message, event);
+        if self.target.to_file() {
+            if let Some(file) = self.file.as_mut() {
+                let _ = writeln!(file, "{}", line);
+            }
+        }
+        if self.target.to_stderr() {
+            eprintln!("{}", line);
+        }
+    }
 }
 
-pub fn log_internal(args: fmt::Arguments) {
-    if let Ok(mut guard) = get_log_file().lock() {
-        if let Some(file) = guard.as_mut() {
-            let _ = writeln!(file, "{}", args);
+fn env_override<T: FromStr<Err = String>>(name: &str) -> Option<T> {
+    let value = env::var(name).ok().filter(|v| !v.is_empty())?;
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(e) => {
+            eprintln!("warning: ignoring {}: {}", name, e);
+            None
         }
     }
 }
 
+// To enable logging to a file, the `logs` directory must exist within the
+// `SMALLWEB_APP_DIR`. If the log file can't be opened (e.g., due to
+// permissions or the directory not existing), file logging is disabled.
+fn open_log_file() -> Option<File> {
+    let app_dir = env::var("SMALLWEB_APP_DIR").ok()?;
+    let path = Path::new(&app_dir)
+        .join("logs")
+        .join("smallweb-wrapper.log");
+    OpenOptions::new().create(true).append(true).open(path).ok()
+}
+
+static LOGGER: OnceLock<Mutex<Logger>> = OnceLock::new();
+
+// Lazily initialized from the environment, so logging works before the
+// settings file has been read.
+fn logger() -> &'static Mutex<Logger> {
+    LOGGER.get_or_init(|| Mutex::new(Logger::from_env()))
+}
+
+/// Applies the `log` section of the settings file. Env vars still win.
+pub fn configure(settings: &LogSettings) {
+    if let Ok(mut logger) = logger().lock() {
+        logger.apply(settings);
+    }
+}
+
+/// Records the app and port, included in every JSON line from now on.
+pub fn set_context(app: Option<String>, port: Option<u16>) {
+    if let Ok(mut logger) = logger().lock() {
+        logger.context.app = app;
+        logger.context.port = port;
+    }
+}
+
+/// Logs one of the key moments of an invocation as a typed event.
+pub fn event(level: Level, event: Event) {
+    if let Ok(mut logger) = logger().lock() {
+        logger.log_event(level, &event);
+    }
+}
+
+/// The id prefixed to this invocation's log lines.
+pub fn invocation_id() -> String {
+    logger()
+        .lock()
+        .map(|logger| logger.invocation_id.clone())
+        .unwrap_or_default()
+}
+
+/// `message` masked the way a log line would be.
+pub fn redact(message: &str) -> String {
+    match logger().lock() {
+        Ok(logger) => logger.redactor.redact(message),
+        Err(_) => Redactor::default().redact(message),
+    }
+}
+
+pub fn log_internal(level: Level, args: fmt::Arguments) {
+    if let Ok(mut logger) = logger().lock() {
+        logger.log(level, args);
+    }
+}
+
+/// `args` quoted the way a shell would need them, for logging.
+pub fn command_line(args: &[String]) -> String {
+    let quoted: Vec<String> = args
+        .iter()
+        .map(|arg| {
+            if arg.is_empty() {
+                "''".to_string()
+            } else if arg
+                .chars()
+                .all(|c| c.is_alphanumeric() || "/_.-".contains(c))
+            {
+                arg.clone()
+            } else {
+                format!("'{}'", arg.replace('\'', "'\\''"))
+            }
+        })
+        .collect();
+    quoted.join(" ")
+}
+
+/// Current UTC time as an RFC 3339 timestamp with millisecond precision.
+pub fn timestamp() -> String {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format_timestamp(since_epoch.as_secs(), since_epoch.subsec_millis())
+}
+
+fn format_timestamp(secs: u64, millis: u32) -> String {
+    let days = (secs / 86_400) as i64;
+    let secs_of_day = secs % 86_400;
+    // Civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3_600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        millis
+    )
+}
+
+#[macro_export]
+macro_rules! error_log {
+    ($($arg:tt)*) => {
+        $crate::logging::log_internal($crate::logging::Level::Error, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! warn_log {
+    ($($arg:tt)*) => {
+        $crate::logging::log_internal($crate::logging::Level::Warn, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! info_log {
+    ($($arg:tt)*) => {
+        $crate::logging::log_internal($crate::logging::Level::Info, format_args!($($arg)*))
+    };
+}
+
 #[macro_export]
 macro_rules! debug_log {
     ($($arg:tt)*) => {
-        $crate::logging::log_internal(format_args!($($arg)*))
+        $crate::logging::log_internal($crate::logging::Level::Debug, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! trace_log {
+    ($($arg:tt)*) => {
+        $crate::logging::log_internal($crate::logging::Level::Trace, format_args!($($arg)*))
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0, 0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(format_timestamp(951_782_400, 5), "2000-02-29T00:00:00.005Z");
+        assert_eq!(
+            format_timestamp(1_792_276_245, 123),
+            "2026-10-17T22:30:45.123Z"
+        );
+    }
+
+    #[test]
+    fn test_level_filtering_and_prefix() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("smallweb-wrapper.log");
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap();
+        let mut logger = Logger::new(Level::Info, Target::File, Some(file));
+
+        logger.log(Level::Error, format_args!("boom"));
+        logger.log(Level::Info, format_args!("spawning"));
+        logger.log(Level::Debug, format_args!("details"));
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let prefix = format!("[{}] [{}]", std::process::id(), logger.invocation_id);
+        assert!(
+            lines[0].ends_with(&format!("Z ERROR {} boom", prefix)),
+            "{}",
+            lines[0]
+        );
+        assert!(
+            lines[1].ends_with(&format!("Z INFO  {} spawning", prefix)),
+            "{}",
+            lines[1]
+        );
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("smallweb-wrapper.log");
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap();
+        let mut logger = Logger::new(Level::Info, Target::File, Some(file));
+        logger.format = Format::Json;
+
+        logger.log(Level::Info, format_args!("starting"));
+        logger.context.app = Some("blog".to_string());
+        logger.context.port = Some(38025);
+        logger.log_event(Level::Info, &Event::Decision { action: "exec" });
+        logger.log_event(
+            Level::Info,
+            &Event::Ready {
+                port: 38025,
+                startup_ms: 420,
+            },
+        );
+        logger.log_event(
+            Level::Warn,
+            &Event::ChildExit {
+                code: None,
+                signal: Some(9),
+                early: false,
+                reason: "oom",
+            },
+        );
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+
+        assert_eq!(lines[0]["message"], "starting");
+        assert_eq!(lines[0]["level"], "info");
+        assert_eq!(lines[0]["app"], Value::Null);
+        assert!(lines[0].get("event").is_none());
+
+        assert_eq!(lines[1]["event"], "decision");
+        assert_eq!(lines[1]["action"], "exec");
+        assert_eq!(lines[1]["app"], "blog");
+
+        assert_eq!(lines[2]["event"], "ready");
+        assert_eq!(lines[2]["port"], 38025);
+        assert_eq!(lines[2]["startup_ms"], 420);
+        assert_eq!(lines[2]["action"], "exec");
+        assert_eq!(
+            lines[2]["message"],
+            "port 38025 is listening after 420ms, READY"
+        );
+
+        assert_eq!(lines[3]["event"], "child_exit");
+        assert_eq!(lines[3]["level"], "warn");
+        assert_eq!(lines[3]["signal"], 9);
+        assert_eq!(lines[3]["code"], Value::Null);
+        assert_eq!(lines[3]["reason"], "oom");
+        assert!(lines[3]["timestamp"].as_str().unwrap().ends_with('Z'));
+    }
+
+    #[test]
+    fn test_secrets_never_reach_log_file() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("smallweb-wrapper.log");
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap();
+        let mut logger = Logger::new(Level::Trace, Target::File, Some(file));
+        logger.redactor = Redactor::new(&["dsn".to_string()], &["sk_live_*".to_string()]);
+
+        logger.log(
+            Level::Info,
+            format_args!("Args: [\"run\", \"--allow-env=STRIPE_KEY\", \"main.ts\"]"),
+        );
+        logger.log(
+            Level::Trace,
+            format_args!("file_content: {{\"exec\": \"./app\", \"adminToken\": \"tok-7f3a\"}}"),
+        );
+        logger.log(
+            Level::Debug,
+            format_args!("env: DATABASE_PASSWORD=hunter2 SENTRY_DSN=dsn-99"),
+        );
+        logger.log(
+            Level::Info,
+            format_args!("upstream postgres://app:pg-s3cret@db:5432/app"),
+        );
+        logger.log(Level::Warn, format_args!("charging with sk_live_51Hx9"));
+        logger.format = Format::Json;
+        logger.log(
+            Level::Info,
+            format_args!("GET /hook?api_key=ak-2b1e&page=1"),
+        );
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content.lines().count(), 6);
+        for secret in [
+            "STRIPE_KEY",
+            "tok-7f3a",
+            "hunter2",
+            "dsn-99",
+            "pg-s3cret",
+            "sk_live_51Hx9",
+            "ak-2b1e",
+        ] {
+            assert!(
+                !content.contains(secret),
+                "{} leaked into:\n{}",
+                secret,
+                content
+            );
+        }
+        assert!(content.contains("\"exec\": \"./app\""));
+        assert!(content.contains("@db:5432/app"));
+        assert!(content.contains("&page=1"));
+    }
+
+    #[test]
+    fn test_command_line_redacted_by_settings() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("smallweb-wrapper.log");
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap();
+        let mut logger = Logger::new(Level::Info, Target::File, Some(file));
+        logger.apply(&LogSettings {
+            redact: Some(vec!["sk_live_*".to_string()]),
+            ..LogSettings::default()
+        });
+
+        let args = [
+            "deno",
+            "run",
+            "--allow-net",
+            "main.ts",
+            "it's sk_live_51Hx9",
+            "",
+        ]
+        .map(String::from);
+        logger.log(Level::Info, format_args!("{}", command_line(&args)));
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(!content.contains("sk_live_51Hx9"), "{}", content);
+        assert!(
+            content.contains(" deno run --allow-net main.ts 'it'\\''s "),
+            "{}",
+            content
+        );
+        assert!(content.trim_end().ends_with(" ''"), "{}", content);
+    }
+
+    #[test]
+    fn test_parse_level_and_target() {
+        assert_eq!("DEBUG".parse::<Level>(), Ok(Level::Debug));
+        assert!("verbose"
+            .parse::<Level>()
+            .unwrap_err()
+            .contains("expected one of: error,"));
+        assert_eq!("both".parse::<Target>(), Ok(Target::Both));
+        assert_eq!("JSON".parse::<Format>(), Ok(Format::Json));
+        assert!(Level::Warn < Level::Trace);
+    }
+}
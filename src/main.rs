@@ -1,75 +1,139 @@
 use std::env;
-use std::io::Write;
 use std::os::unix::process::CommandExt;
-use std::process::{Command, Stdio};
-use std::thread;
-use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
 
+#[macro_use]
+mod schema;
 #[macro_use]
 mod logging;
+mod applog;
+mod build;
+mod config;
 mod core;
+mod diagnosis;
+mod environment;
+mod fileserver;
+mod history;
+mod jsonc;
 mod linux;
-use crate::core::{decide_action, Action};
-
-fn check_child_status(child: &mut std::process::Child) {
-    match child.try_wait() {
-        Ok(Some(status)) => {
-            eprintln!("error: child process exited early with status: {}", status);
-            std::process::exit(status.code().unwrap_or(1));
-        }
-        Ok(None) => { /* child is still running */ }
+mod proxy;
+mod runtime;
+mod settings;
+mod sidecar;
+mod supervisor;
+use crate::config::parse_config;
+use crate::core::{decide_action, find_config, Action, EXEC_MARKER_ENV};
+use crate::history::Invocation;
+use crate::logging::{Event, Level};
+use crate::settings::Settings;
+use crate::supervisor::{Inbound, Listen, Readiness};
+
+/// Checks the adapter config of the app in `dir` and prints every problem
+/// found. Returns the process exit code.
+fn validate(dir: &Path) -> i32 {
+    let Some(config_path) = find_config(Some(dir), None) else {
+        eprintln!(
+            "error: no smallweb.json or smallweb.jsonc in {}",
+            dir.display()
+        );
+        return 1;
+    };
+    let content = match std::fs::read_to_string(&config_path) {
+        Ok(content) => content,
         Err(e) => {
-            eprintln!("error: failed to check child process status: {}", e);
-            let _ = child.kill();
-            std::process::exit(1);
+            eprintln!("error: failed to read {}: {}", config_path.display(), e);
+            return 1;
+        }
+    };
+    match parse_config(&content) {
+        Ok(Some(_)) => {
+            println!("{}: ok", config_path.display());
+            0
+        }
+        Ok(None) => {
+            println!(
+                "{}: ok (no adapter settings, runs with deno)",
+                config_path.display()
+            );
+            0
+        }
+        Err(errors) => {
+            for error in &errors {
+                match error.position {
+                    Some((line, column)) => eprintln!(
+                        "{}:{}:{}: {}",
+                        config_path.display(),
+                        line,
+                        column,
+                        error.message
+                    ),
+                    None => eprintln!("{}: {}", config_path.display(), error.message),
+                }
+            }
+            eprintln!("{} error(s) found", errors.len());
+            1
         }
     }
 }
 
-fn spawn_and_wait_for_port(command: &mut Command, port: u16, shell_script: Option<&str>) {
-    if shell_script.is_some() {
-        command.stdin(Stdio::piped());
+/// Prints the recorded invocations, oldest first. Accepts `--app NAME|DIR`,
+/// `--limit N` (default 20) and `--json`. Returns the process exit code.
+fn print_history(args: &[String]) -> i32 {
+    let mut app = None;
+    let mut limit = 20;
+    let mut json = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--app" => app = args.next().cloned(),
+            "--limit" => match args.next().map(|n| n.parse()) {
+                Some(Ok(n)) => limit = n,
+                _ => {
+                    eprintln!("error: --limit expects a number");
+                    return 1;
+                }
+            },
+            "--json" => json = true,
+            other => {
+                eprintln!("error: unexpected argument {:?}", other);
+                eprintln!("usage: not-deno history [--app NAME|DIR] [--limit N] [--json]");
+                return 1;
+            }
+        }
     }
-    let mut child = match command.spawn() {
-        Ok(child) => child,
+
+    let settings = match Settings::load() {
+        Ok(settings) => settings,
         Err(e) => {
-            eprintln!("Failed to spawn child process: {}", e);
-            std::process::exit(1);
+            eprintln!("error: {}", e);
+            return 1;
         }
     };
-
-    if let Some(script) = shell_script {
-        if let Some(mut stdin) = child.stdin.take() {
-            if let Err(e) = stdin.write_all(script.as_bytes()) {
-                eprintln!("error: failed to write to child stdin: {}", e);
-                let _ = child.kill();
-                std::process::exit(1);
-            }
+    let path = match settings.history.path() {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return 1;
         }
-    }
-
-    let start = Instant::now();
-    let timeout = Duration::from_secs(30);
-
-    loop {
-        check_child_status(&mut child);
-
-        if linux::is_port_listening(port) {
-            eprintln!("READY");
-            break;
+    };
+    let records = match history::read(&path) {
+        Ok(records) => records,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => {
+            eprintln!("error: failed to read {}: {}", path.display(), e);
+            return 1;
         }
-
-        if start.elapsed() > timeout {
-            eprintln!("error: timed out waiting for port {}", port);
-            let _ = child.kill();
-            std::process::exit(1);
+    };
+    for record in history::filter(records, app.as_deref(), limit) {
+        if json {
+            println!("{}", serde_json::to_string(&record).unwrap());
+        } else {
+            println!("{}", history::format_record(&record));
         }
-
-        thread::sleep(Duration::from_millis(100));
     }
-
-    let status = child.wait().expect("Failed to wait on child process");
-    std::process::exit(status.code().unwrap_or(1));
+    0
 }
 
 fn main() {
@@ -86,74 +150,339 @@ fn main() {
         std::process::exit(0);
     }
 
-    let cwd = env::current_dir().map_or_else(|_| "unknown".to_string(), |p| p.display().to_string());
-    debug_log!("CWD: {}", cwd);
+    if args.iter().any(|arg| arg == "--smallweb-adapter-schema") {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&config::schema()).unwrap()
+        );
+        std::process::exit(0);
+    }
 
-    let quoted_args: Vec<String> = args
-        .iter()
-        .map(|arg| {
-            if arg.is_empty() {
-                "''".to_string()
-            } else if arg
-                .chars()
-                .all(|c| c.is_alphanumeric() || "/_.-".contains(c))
-            {
-                arg.clone()
-            } else {
-                format!("'{}'", arg.replace('\'', "'\\''"))
+    // Started by the adapter itself inside a sandbox with `listen_port`.
+    if args.get(1).map(String::as_str) == Some(proxy::FORWARD_FLAG) {
+        match (args.get(2), args.get(3).and_then(|port| port.parse().ok())) {
+            (Some(socket), Some(port)) => proxy::forward_to_port(Path::new(socket), port),
+            _ => {
+                eprintln!("usage: not-deno {} SOCKET PORT", proxy::FORWARD_FLAG);
+                std::process::exit(1);
             }
-        })
-        .collect();
-    debug_log!("{}", quoted_args.join(" "));
+        }
+    }
 
-	// workaround for smallweb not passing PATH through
-    let mut path_var = env::var("PATH").unwrap_or_default();
-    // smallweb likes to remove all ENV vars
-    // put PATH back
-    if path_var.is_empty() {
-        if let Ok(output) = Command::new("/bin/bash")
-            .arg("--login")
-            .arg("-c")
-            .arg("echo $PATH")
-            .output()
-        {
-            if output.status.success() {
-                path_var = String::from_utf8_lossy(&output.stdout).trim().to_string();
-                env::set_var("PATH", &path_var);
+    // Started by the adapter itself inside a sandbox with `sidecars`.
+    if args.get(1).map(String::as_str) == Some(sidecar::RUN_FLAG) {
+        let shutdown_timeout = args.get(2).and_then(|secs| secs.parse().ok());
+        let sidecars = args.get(3).and_then(|spec| serde_json::from_str(spec).ok());
+        match (shutdown_timeout, sidecars) {
+            (Some(secs), Some(sidecars)) if args.len() > 4 => {
+                sidecar::run(sidecars, Duration::from_secs(secs), &args[4..])
+            }
+            _ => {
+                eprintln!(
+                    "usage: not-deno {} SHUTDOWN_SECS SIDECARS COMMAND...",
+                    sidecar::RUN_FLAG
+                );
+                std::process::exit(1);
             }
         }
     }
 
-    let (action, own_abs_path) = decide_action(&args, &path_var);
+    if args.get(1).map(String::as_str) == Some("validate") {
+        let dir = args
+            .get(2)
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("SMALLWEB_APP_DIR").map(PathBuf::from))
+            .unwrap_or_else(|| PathBuf::from("."));
+        std::process::exit(validate(&dir));
+    }
+
+    if args.get(1).map(String::as_str) == Some("history") {
+        std::process::exit(print_history(&args[2..]));
+    }
+
+    let cwd =
+        env::current_dir().map_or_else(|_| "unknown".to_string(), |p| p.display().to_string());
+    debug_log!("CWD: {}", cwd);
+
+    if env::var(EXEC_MARKER_ENV).ok() == Some(std::process::id().to_string()) {
+        eprintln!(
+            "error: exec loop detected, \"deno\" resolved back to smallweb-adapter. Set {} to the real deno binary.",
+            settings::DENO_ENV
+        );
+        std::process::exit(1);
+    }
+
+    let settings = match Settings::load() {
+        Ok(settings) => settings,
+        Err(e) => {
+            error_log!("{}", e);
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    logging::configure(&settings.log);
+    // Only now that redaction is configured: arguments can carry secrets.
+    info_log!("{}", logging::command_line(&args));
+
+    // smallweb likes to remove all ENV vars, put PATH back
+    let mut path_var = env::var("PATH").unwrap_or_default();
+    if path_var.is_empty() {
+        path_var = environment::recover_path(&settings.env);
+        debug_log!("recovered PATH: {}", path_var);
+        env::set_var("PATH", &path_var);
+    }
+
+    let app_dir = env::var_os("SMALLWEB_APP_DIR").map(PathBuf::from);
+    let (action, own_abs_path) = decide_action(&args, &path_var, app_dir.as_deref(), &settings);
+
+    let app_name = env::var("SMALLWEB_APP_NAME").ok().or_else(|| {
+        app_dir
+            .as_deref()
+            .and_then(Path::file_name)
+            .map(|name| name.to_string_lossy().into_owned())
+    });
+    let port = match &action {
+        Action::Exec(_, deno_args) | Action::Proxy { deno_args, .. } => Some(deno_args.port),
+        _ => None,
+    };
+    logging::set_context(app_name.clone(), port);
+    logging::event(
+        Level::Info,
+        Event::Decision {
+            action: action.kind(),
+        },
+    );
+    let mut invocation = Invocation::start(
+        &settings.history,
+        app_name,
+        app_dir.clone(),
+        action.kind(),
+        &args,
+    );
     match action {
+        Action::Proxy {
+            upstream,
+            idle_timeout,
+            deno_args,
+        } => proxy::serve(deno_args.port, upstream, invocation, idle_timeout),
+        Action::Exec(config, deno_args) if config.serves_static() => {
+            let app_dir = app_dir
+                .or_else(|| env::current_dir().ok())
+                .unwrap_or_else(|| PathBuf::from("."));
+            if let Some(build_config) = &config.build {
+                let bwrap_args = linux::deno_sandbox_to_bubblewrap_args(&args, &own_abs_path);
+                if let Err(e) = build::run(build_config, &app_dir, &bwrap_args) {
+                    error_log!("{}", e);
+                    eprintln!("error: {}", e);
+                    invocation.finish(Some(1));
+                    std::process::exit(1);
+                }
+            }
+            let site = config
+                .r#static
+                .unwrap_or_else(|| fileserver::StaticSite::default_for(&app_dir));
+            let idle_timeout = config.idle_timeout.map(Duration::from_secs);
+            fileserver::serve(deno_args.port, &site, &app_dir, invocation, idle_timeout)
+        }
         Action::Exec(config, deno_args) => {
-            let bwrap_args = linux::deno_sandbox_to_bubblewrap_args(&args, &own_abs_path);
+            let app_dir = app_dir
+                .or_else(|| env::current_dir().ok())
+                .unwrap_or_else(|| PathBuf::from("."));
+            let mut bwrap_args = linux::deno_sandbox_to_bubblewrap_args(&args, &own_abs_path);
+            let preset = config
+                .runtime
+                .and_then(|runtime| runtime.preset(&app_dir, &path_var));
+            if let Some(preset) = &preset {
+                bwrap_args.extend(
+                    preset
+                        .mounts
+                        .iter()
+                        .filter_map(|path| linux::bind_mount(&path.display().to_string(), false))
+                        .flatten(),
+                );
+            }
+            if let Some(build_config) = &config.build {
+                if let Err(e) = build::run(build_config, &app_dir, &bwrap_args) {
+                    error_log!("{}", e);
+                    eprintln!("error: {}", e);
+                    invocation.finish(Some(1));
+                    std::process::exit(1);
+                }
+            }
+            let socket_activation = config.socket_activation.unwrap_or(false);
+            let inbound = if let Some(socket) = &config.socket {
+                match supervisor::SocketDir::create(socket) {
+                    Ok(socket_dir) => {
+                        bwrap_args.extend(socket_dir.bwrap_args());
+                        Inbound::Socket(socket_dir)
+                    }
+                    Err(e) => {
+                        error_log!("failed to create socket directory: {}", e);
+                        eprintln!("error: failed to create socket directory: {}", e);
+                        invocation.finish(Some(1));
+                        std::process::exit(1);
+                    }
+                }
+            } else if socket_activation {
+                match proxy::bind(deno_args.port) {
+                    Ok(listener) => {
+                        // Inbound connections arrive on the passed listener,
+                        // so the app only gets a network if it asked for one.
+                        if !args.iter().any(|arg| arg == "--allow-net") {
+                            bwrap_args.push("--unshare-net".to_string());
+                        }
+                        Inbound::Activated(listener)
+                    }
+                    Err(e) => {
+                        error_log!("failed to bind port {}: {}", deno_args.port, e);
+                        eprintln!("error: failed to bind port {}: {}", deno_args.port, e);
+                        invocation.finish(Some(1));
+                        std::process::exit(1);
+                    }
+                }
+            } else if let Some(inner_port) = config.listen_port {
+                match supervisor::SocketDir::for_forwarder() {
+                    Ok(socket_dir) => {
+                        // The app gets loopback only, where its fixed port
+                        // can't clash with anything else.
+                        bwrap_args.retain(|arg| arg != "--share-net");
+                        bwrap_args.push("--unshare-net".to_string());
+                        bwrap_args.extend(socket_dir.bwrap_args());
+                        let own_path = own_abs_path.display().to_string();
+                        bwrap_args.extend(["--ro-bind".to_string(), own_path.clone(), own_path]);
+                        Inbound::Remapped {
+                            socket_dir,
+                            port: inner_port,
+                        }
+                    }
+                    Err(e) => {
+                        error_log!("failed to create socket directory: {}", e);
+                        eprintln!("error: failed to create socket directory: {}", e);
+                        invocation.finish(Some(1));
+                        std::process::exit(1);
+                    }
+                }
+            } else {
+                Inbound::Port
+            };
+            let notify = if config.ready == Some(Readiness::Notify) {
+                match supervisor::NotifySocket::create() {
+                    Ok(notify) => {
+                        bwrap_args.extend(notify.bwrap_args());
+                        Some(notify)
+                    }
+                    Err(e) => {
+                        error_log!("failed to create notify socket: {}", e);
+                        eprintln!("error: failed to create notify socket: {}", e);
+                        invocation.finish(Some(1));
+                        std::process::exit(1);
+                    }
+                }
+            } else {
+                None
+            };
+            let sidecars = config.sidecars.clone().unwrap_or_default();
+            let own_path = own_abs_path.display().to_string();
+            if !sidecars.is_empty() && !bwrap_args.contains(&own_path) {
+                bwrap_args.extend(["--ro-bind".to_string(), own_path.clone(), own_path.clone()]);
+            }
+            invocation.set_mounts(&bwrap_args);
             let mut command = Command::new("bwrap");
             command.args(&bwrap_args);
             command.arg("--");
+            if !sidecars.is_empty() {
+                // The adapter starts the sidecars in the sandbox, then the
+                // shell, and stops them all together.
+                let shutdown_secs = config.shutdown_timeout.unwrap_or(10);
+                let spec = serde_json::to_string(&sidecars).unwrap_or_default();
+                command.args([
+                    own_path,
+                    sidecar::RUN_FLAG.to_string(),
+                    shutdown_secs.to_string(),
+                    spec,
+                ]);
+            }
             command.arg("/bin/sh");
-            command.env("PORT", deno_args.port.to_string());
-            let shell_script = format!("set -x\n{}", &config.exec);
+            let app_port = config.listen_port.unwrap_or(deno_args.port);
+            command.env("PORT", app_port.to_string());
+            if let Some(socket) = &config.socket {
+                command.env("SOCKET", socket);
+            }
+            if let Some(notify) = &notify {
+                command.env("NOTIFY_SOCKET", notify.path());
+            }
+            let mut shell_script = String::from("set -x\n");
+            if socket_activation {
+                // Only the shell's own PID inside the sandbox is known; apps
+                // that check LISTEN_PID have to be started with `exec`.
+                shell_script.insert_str(0, "export LISTEN_PID=$$\n");
+            }
+            if let Inbound::Remapped { socket_dir, port } = &inbound {
+                let forwarder = [
+                    own_abs_path.display().to_string(),
+                    proxy::FORWARD_FLAG.to_string(),
+                    socket_dir.socket().display().to_string(),
+                    port.to_string(),
+                ];
+                let quoted: Vec<String> = forwarder
+                    .iter()
+                    .map(|arg| format!("'{}'", arg.replace('\'', "'\\''")))
+                    .collect();
+                shell_script.insert_str(0, &format!("{} &\n", quoted.join(" ")));
+            }
+            let exec = config
+                .exec
+                .as_deref()
+                .or(preset.as_ref().map(|preset| preset.exec.as_str()));
+            shell_script.push_str(exec.unwrap_or_default());
             let bwrap_command_str = format!("{:?}", &command);
             // escape for single-quoted shell string
             let shell_script_escaped = format!("'{}'", shell_script.replace('\'', "'\\''"));
-            debug_log!(
+            info_log!(
                 "Spawning command:\nPORT={} echo {} | {}",
-                deno_args.port,
+                app_port,
                 shell_script_escaped,
                 bwrap_command_str
             );
-            spawn_and_wait_for_port(&mut command, deno_args.port, Some(&shell_script));
+            supervisor::spawn_and_wait_for_port(
+                &mut command,
+                Listen {
+                    port: deno_args.port,
+                    inbound,
+                    notify,
+                },
+                supervisor::STARTUP_TIMEOUT,
+                Some(&shell_script),
+                &config,
+                &app_dir,
+                invocation,
+            );
+        }
+        Action::Abort(message) => {
+            error_log!("{}", message);
+            eprintln!("error: {}", message);
+            invocation.finish(Some(1));
+            std::process::exit(1);
+        }
+        Action::InvalidConfig(path, message) => {
+            error_log!("invalid {}: {}", path.display(), message);
+            eprintln!("error: invalid {}:\n{}", path.display(), message);
+            invocation.finish(Some(1));
+            std::process::exit(1);
         }
-        Action::ExecDeno { new_path } => {
-            let mut command = Command::new("deno");
+        Action::ExecDeno { new_path, deno } => {
+            let mut command = Command::new(deno.unwrap_or_else(|| PathBuf::from("deno")));
             command.args(&args[1..]);
+            command.env(EXEC_MARKER_ENV, std::process::id().to_string());
             if let Some(p) = new_path {
                 command.env("PATH", p);
             }
-            debug_log!("Executing command: {:?}", &command);
+            info_log!("Executing command: {:?}", &command);
+            // exec only returns on failure, so record the handover up front.
+            invocation.finish(None);
             let err = command.exec();
-            eprintln!("Failed to exec deno: {}", err);
+            error_log!("Failed to exec {:?}: {}", command.get_program(), err);
+            eprintln!("Failed to exec {:?}: {}", command.get_program(), err);
             std::process::exit(1);
         }
     }
@@ -162,8 +491,11 @@ fn main() {
 #[cfg(test)]
 mod tests {
     use crate::core::{decide_action, Action, DenoArgs};
+    use crate::proxy::Upstream;
+    use crate::settings::Settings;
     use std::env;
     use std::path::{Path, PathBuf};
+    use std::time::Duration;
     use tempfile::tempdir;
 
     #[test]
@@ -194,7 +526,7 @@ mod tests {
             dir, args_str
         );
 
-        let (action, own_abs_path) = decide_action(&args, path_var);
+        let (action, own_abs_path) = decide_action(&args, path_var, None, &Settings::default());
         assert_eq!(
             own_abs_path,
             std::fs::canonicalize(&fake_deno_path).unwrap()
@@ -207,7 +539,7 @@ mod tests {
 
         match action {
             Action::Exec(config, deno_args) => {
-                assert!(config.exec.contains("$PORT"));
+                assert!(config.exec.unwrap().contains("$PORT"));
                 assert_eq!(deno_args, expected_deno_args);
             }
             _ => panic!("Expected Action::Exec, but got {:?}", action),
@@ -249,11 +581,13 @@ mod tests {
         )
         .unwrap();
 
-        let (action, own_abs_path) = decide_action(&args, original_path.to_str().unwrap());
-        assert_eq!(
-            own_abs_path,
-            std::fs::canonicalize(&adapter_path).unwrap()
+        let (action, own_abs_path) = decide_action(
+            &args,
+            original_path.to_str().unwrap(),
+            None,
+            &Settings::default(),
         );
+        assert_eq!(own_abs_path, std::fs::canonicalize(&adapter_path).unwrap());
 
         let expected_new_path =
             env::join_paths([deno_dir, Path::new("/usr/bin"), Path::new("/bin")].iter()).unwrap();
@@ -261,7 +595,8 @@ mod tests {
         assert_eq!(
             action,
             Action::ExecDeno {
-                new_path: Some(expected_new_path)
+                new_path: Some(expected_new_path),
+                deno: None,
             }
         );
     }
@@ -299,11 +634,13 @@ mod tests {
         )
         .unwrap();
 
-        let (action, own_abs_path) = decide_action(&args, original_path.to_str().unwrap());
-        assert_eq!(
-            own_abs_path,
-            std::fs::canonicalize(&adapter_path).unwrap()
+        let (action, own_abs_path) = decide_action(
+            &args,
+            original_path.to_str().unwrap(),
+            None,
+            &Settings::default(),
         );
+        assert_eq!(own_abs_path, std::fs::canonicalize(&adapter_path).unwrap());
 
         let expected_new_path =
             env::join_paths([deno_dir, Path::new("/usr/bin"), Path::new("/bin")].iter()).unwrap();
@@ -311,11 +648,180 @@ mod tests {
         assert_eq!(
             action,
             Action::ExecDeno {
-                new_path: Some(expected_new_path)
+                new_path: Some(expected_new_path),
+                deno: None,
             }
         );
     }
 
+    #[test]
+    fn test_entrypoint_in_subdirectory() {
+        // smallweb.json lives in the app root, the entrypoint in dist/.
+        let app_dir = tempdir().unwrap();
+        std::fs::write(
+            app_dir.path().join("smallweb.json"),
+            r#"{"exec":"./server --port $PORT"}"#,
+        )
+        .unwrap();
+        let dist_dir = app_dir.path().join("dist");
+        std::fs::create_dir(&dist_dir).unwrap();
+        std::fs::File::create(dist_dir.join("main.ts")).unwrap();
+
+        let temp_dir_adapter = tempdir().unwrap();
+        let adapter_path = temp_dir_adapter.path().join("deno");
+        std::fs::File::create(&adapter_path).unwrap();
+
+        let json_arg = format!(
+            r#"{{"command":"fetch","entrypoint":"file://{}/main.ts","port":38025}}"#,
+            dist_dir.to_str().unwrap()
+        );
+        let args = vec![
+            adapter_path.to_str().unwrap().to_string(),
+            "run".to_string(),
+            json_arg,
+        ];
+
+        // Without an app dir only the entrypoint's own directory is checked.
+        let (action, _) = decide_action(&args, "/usr/bin:/bin", None, &Settings::default());
+        assert!(matches!(action, Action::ExecDeno { .. }));
+
+        let (action, _) = decide_action(
+            &args,
+            "/usr/bin:/bin",
+            Some(app_dir.path()),
+            &Settings::default(),
+        );
+        match action {
+            Action::Exec(config, _) => {
+                assert_eq!(config.exec.as_deref(), Some("./server --port $PORT"))
+            }
+            _ => panic!("Expected Action::Exec, but got {:?}", action),
+        }
+    }
+
+    #[test]
+    fn test_proxy_decided_with_upstream() {
+        let app_dir = tempdir().unwrap();
+        let temp_dir_adapter = tempdir().unwrap();
+        let adapter_path = temp_dir_adapter.path().join("deno");
+        std::fs::File::create(&adapter_path).unwrap();
+        let args = vec![
+            adapter_path.to_str().unwrap().to_string(),
+            "run".to_string(),
+            "-".to_string(),
+            r#"{"command":"fetch","entrypoint":"jsr:@smallweb/file-server@0.8.2","port":42541}"#
+                .to_string(),
+        ];
+        let decide = |config: &str| {
+            std::fs::write(app_dir.path().join("smallweb.json"), config).unwrap();
+            decide_action(
+                &args,
+                "/usr/bin:/bin",
+                Some(app_dir.path()),
+                &Settings::default(),
+            )
+            .0
+        };
+
+        match decide(r#"{"adapter":{"proxy":"http://10.0.0.5:8080","idle_timeout":60}}"#) {
+            Action::Proxy {
+                upstream,
+                idle_timeout,
+                deno_args,
+            } => {
+                assert_eq!(upstream, Upstream::Tcp("10.0.0.5:8080".to_string()));
+                assert_eq!(idle_timeout, Some(Duration::from_secs(60)));
+                assert_eq!(deno_args.port, 42541);
+            }
+            action => panic!("Expected Action::Proxy, but got {:?}", action),
+        }
+        // Proxy targets are outside services: any socket may be named.
+        match decide(r#"{"adapter":{"proxy":"unix:/run/app.sock"}}"#) {
+            Action::Proxy { upstream, .. } => {
+                assert_eq!(upstream, Upstream::Unix(PathBuf::from("/run/app.sock")))
+            }
+            action => panic!("Expected Action::Proxy, but got {:?}", action),
+        }
+    }
+
+    #[test]
+    fn test_jsr_entrypoint_with_app_dir() {
+        // An app dir holding nothing but smallweb.json is launched by Smallweb
+        // with its default jsr: entrypoint.
+        let app_dir = tempdir().unwrap();
+        std::fs::write(
+            app_dir.path().join("smallweb.json"),
+            r#"{"exec":"python3 -m http.server $PORT"}"#,
+        )
+        .unwrap();
+
+        let temp_dir_adapter = tempdir().unwrap();
+        let adapter_path = temp_dir_adapter.path().join("deno");
+        std::fs::File::create(&adapter_path).unwrap();
+
+        let args = vec![
+            adapter_path.to_str().unwrap().to_string(),
+            "run".to_string(),
+            "-".to_string(),
+            r#"{"command":"fetch","entrypoint":"jsr:@smallweb/file-server@0.8.2","port":42541}"#
+                .to_string(),
+        ];
+
+        let (action, _) = decide_action(
+            &args,
+            "/usr/bin:/bin",
+            Some(app_dir.path()),
+            &Settings::default(),
+        );
+        match action {
+            Action::Exec(config, deno_args) => {
+                assert!(config.exec.unwrap().contains("$PORT"));
+                assert_eq!(deno_args.port, 42541);
+            }
+            _ => panic!("Expected Action::Exec, but got {:?}", action),
+        }
+    }
+
+    #[test]
+    fn test_configured_deno() {
+        let temp_dir = tempdir().unwrap();
+        let adapter_path = temp_dir.path().join("not-deno");
+        std::fs::File::create(&adapter_path).unwrap();
+        // The real deno lives in the same directory as the adapter.
+        let real_deno = temp_dir.path().join("deno");
+        std::fs::File::create(&real_deno).unwrap();
+
+        let args = vec![
+            adapter_path.to_str().unwrap().to_string(),
+            "run".to_string(),
+            "foo.ts".to_string(),
+        ];
+        let path_var = temp_dir.path().to_str().unwrap();
+
+        let settings = Settings {
+            deno: Some(real_deno.clone()),
+            ..Settings::default()
+        };
+        let (action, _) = decide_action(&args, path_var, None, &settings);
+        assert_eq!(
+            action,
+            Action::ExecDeno {
+                new_path: None,
+                deno: Some(real_deno),
+            }
+        );
+
+        // A symlink back to the adapter must not be exec'd.
+        let symlink = temp_dir.path().join("deno-link");
+        std::os::unix::fs::symlink(&adapter_path, &symlink).unwrap();
+        let settings = Settings {
+            deno: Some(symlink),
+            ..Settings::default()
+        };
+        let (action, _) = decide_action(&args, path_var, None, &settings);
+        assert!(matches!(action, Action::Abort(_)), "{:?}", action);
+    }
+
     #[test]
     #[cfg(unix)]
     fn test_path_canonicalization_with_symlink() {
@@ -367,7 +873,12 @@ mod tests {
         ];
 
         // 3. Run decide_action and assert
-        let (action, own_abs_path) = decide_action(&args, original_path.to_str().unwrap());
+        let (action, own_abs_path) = decide_action(
+            &args,
+            original_path.to_str().unwrap(),
+            None,
+            &Settings::default(),
+        );
         assert_eq!(
             own_abs_path,
             std::fs::canonicalize(&messy_adapter_path).unwrap()
@@ -380,7 +891,8 @@ mod tests {
         assert_eq!(
             action,
             Action::ExecDeno {
-                new_path: Some(expected_new_path)
+                new_path: Some(expected_new_path),
+                deno: None,
             }
         );
     }
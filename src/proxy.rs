@@ -0,0 +1,396 @@
+use crate::history::Invocation;
+use crate::linux;
+use crate::logging::{self, Event, Level};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Runs `forward_to_port` instead of the adapter, inside the sandbox of an
+/// app with a `listen_port`.
+pub const FORWARD_FLAG: &str = "--smallweb-adapter-forward";
+
+/// Where proxy mode forwards connections to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Upstream {
+    /// `host:port`, from `http://host:port` or `tcp://host:port`.
+    Tcp(String),
+    /// A Unix socket, from `unix:/path`.
+    Unix(PathBuf),
+}
+
+impl Upstream {
+    pub fn parse(spec: &str) -> Result<Upstream, String> {
+        if let Some(path) = spec.strip_prefix("unix:") {
+            let path = PathBuf::from(path.strip_prefix("//").unwrap_or(path));
+            if !path.is_absolute() {
+                return Err(format!("unix socket path in `{}` must be absolute", spec));
+            }
+            return Ok(Upstream::Unix(path));
+        }
+        let (rest, default_port) = if let Some(rest) = spec.strip_prefix("http://") {
+            (rest, Some(80))
+        } else if let Some(rest) = spec.strip_prefix("tcp://") {
+            (rest, None)
+        } else if spec.starts_with("https://") {
+            return Err(format!(
+                "`{}`: https upstreams are not supported, connections are forwarded as they are",
+                spec
+            ));
+        } else {
+            return Err(format!(
+                "`{}`: expected `http://host:port`, `tcp://host:port` or `unix:/path`",
+                spec
+            ));
+        };
+        // Connections are forwarded byte for byte, so any path is meaningless.
+        let authority = rest.split('/').next().unwrap_or_default();
+        if authority.is_empty() || authority.contains('@') {
+            return Err(format!("`{}`: expected a host and port", spec));
+        }
+        let has_port = authority
+            .rsplit_once(':')
+            .is_some_and(|(host, port)| !host.ends_with(':') && port.parse::<u16>().is_ok());
+        match (has_port, default_port) {
+            (true, _) => Ok(Upstream::Tcp(authority.to_string())),
+            (false, Some(port)) => Ok(Upstream::Tcp(format!("{}:{}", authority, port))),
+            (false, None) => Err(format!("`{}`: missing port", spec)),
+        }
+    }
+
+    fn connect(&self) -> io::Result<Stream> {
+        match self {
+            Upstream::Tcp(address) => TcpStream::connect(address).map(Stream::Tcp),
+            Upstream::Unix(path) => UnixStream::connect(path).map(Stream::Unix),
+        }
+    }
+}
+
+impl std::fmt::Display for Upstream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Upstream::Tcp(address) => write!(f, "{}", address),
+            Upstream::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Either kind of upstream connection.
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+
+    fn shutdown_write(&self) {
+        let _ = match self {
+            Stream::Tcp(stream) => stream.shutdown(Shutdown::Write),
+            Stream::Unix(stream) => stream.shutdown(Shutdown::Write),
+        };
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Copies bytes both ways between `client` and a new connection to
+/// `upstream` until both sides are done.
+fn forward(client: Stream, upstream: &Upstream) -> io::Result<()> {
+    let server = upstream.connect()?;
+    let (mut client_read, mut server_write) = (client.try_clone()?, server.try_clone()?);
+    let requests = thread::spawn(move || {
+        let _ = io::copy(&mut client_read, &mut server_write);
+        server_write.shutdown_write();
+    });
+    let (mut server_read, mut client_write) = (server, client);
+    let _ = io::copy(&mut server_read, &mut client_write);
+    client_write.shutdown_write();
+    let _ = requests.join();
+    Ok(())
+}
+
+/// Binds `port` on localhost, where Smallweb connects to the app.
+pub fn bind(port: u16) -> io::Result<TcpListener> {
+    TcpListener::bind(("127.0.0.1", port))
+}
+
+/// Connections passing through the adapter, for the idle timer.
+pub struct Activity {
+    open: AtomicUsize,
+    last: Mutex<Instant>,
+}
+
+impl Activity {
+    pub fn new() -> Activity {
+        Activity {
+            open: AtomicUsize::new(0),
+            last: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Records a connection starting.
+    pub fn opened(&self) {
+        self.open.fetch_add(1, Ordering::SeqCst);
+        self.touch();
+    }
+
+    /// Records a connection ending.
+    pub fn closed(&self) {
+        self.touch();
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Number of connections open right now.
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::SeqCst)
+    }
+
+    /// Records activity now.
+    pub fn touch(&self) {
+        *self.last.lock().unwrap() = Instant::now();
+    }
+
+    /// How long there have been no connections, zero while one is open.
+    pub fn idle_for(&self) -> Duration {
+        if self.open() > 0 {
+            return Duration::ZERO;
+        }
+        self.last.lock().unwrap().elapsed()
+    }
+}
+
+/// Forwards `client` to `upstream` from a thread of its own.
+fn spawn_forward(client: Stream, upstream: &Upstream, activity: &Arc<Activity>) {
+    let upstream = upstream.clone();
+    let activity = Arc::clone(activity);
+    activity.opened();
+    thread::spawn(move || {
+        if let Err(e) = forward(client, &upstream) {
+            warn_log!("failed to connect to upstream {}: {}", upstream, e);
+        }
+        activity.closed();
+    });
+}
+
+/// Forwards every connection accepted on `listener` to `upstream` from a
+/// background thread, for as long as the adapter runs.
+pub fn spawn_bridge(listener: TcpListener, upstream: Upstream, activity: Arc<Activity>) {
+    thread::spawn(move || loop {
+        match listener.accept() {
+            Ok((client, peer)) => {
+                trace_log!("proxying connection from {}", peer);
+                spawn_forward(Stream::Tcp(client), &upstream, &activity);
+            }
+            Err(e) => {
+                warn_log!("failed to accept connection: {}", e);
+                thread::sleep(Duration::from_millis(20));
+            }
+        }
+    });
+}
+
+/// Runs inside a sandbox with a network namespace of its own: forwards
+/// every connection to the Unix `socket`, where the adapter outside sends
+/// them, to `port` on the sandbox's loopback. Never returns.
+pub fn forward_to_port(socket: &Path, port: u16) -> ! {
+    // Left over by the app's previous run.
+    let _ = fs::remove_file(socket);
+    let listener = match UnixListener::bind(socket) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("error: failed to bind {}: {}", socket.display(), e);
+            std::process::exit(1);
+        }
+    };
+    let upstream = Upstream::Tcp(format!("127.0.0.1:{}", port));
+    let activity = Arc::new(Activity::new());
+    loop {
+        match listener.accept() {
+            Ok((client, _)) => spawn_forward(Stream::Unix(client), &upstream, &activity),
+            Err(e) => {
+                warn_log!("failed to accept connection: {}", e);
+                thread::sleep(Duration::from_millis(20));
+            }
+        }
+    }
+}
+
+/// Binds `port`, prints READY and forwards every connection to `upstream`
+/// until a termination signal arrives, or no connection did for
+/// `idle_timeout`. Never returns.
+pub fn serve(
+    port: u16,
+    upstream: Upstream,
+    invocation: Invocation,
+    idle_timeout: Option<Duration>,
+) -> ! {
+    info_log!("proxying port {} to {}", port, upstream);
+    front(port, invocation, idle_timeout, |listener, activity| {
+        spawn_bridge(listener, upstream, activity)
+    })
+}
+
+/// Binds `port`, hands the listener to `start`, prints READY and waits for a
+/// termination signal, or for `idle_timeout` without connections. For modes
+/// where the adapter answers on the port itself. Never returns.
+pub fn front(
+    port: u16,
+    invocation: Invocation,
+    idle_timeout: Option<Duration>,
+    start: impl FnOnce(TcpListener, Arc<Activity>),
+) -> ! {
+    let started = Instant::now();
+    linux::install_signal_handlers();
+    let listener = match bind(port) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error_log!("failed to bind port {}: {}", port, e);
+            eprintln!("error: failed to bind port {}: {}", port, e);
+            invocation.finish(Some(1));
+            std::process::exit(1);
+        }
+    };
+    let activity = Arc::new(Activity::new());
+    start(listener, Arc::clone(&activity));
+    let startup_ms = started.elapsed().as_millis() as u64;
+    logging::event(Level::Info, Event::Ready { port, startup_ms });
+    eprintln!("READY");
+
+    loop {
+        if let Some(signal) = linux::take_pending_signal() {
+            info_log!("received signal {}, stopping", signal);
+            invocation.finish(Some(128 + signal));
+            std::process::exit(128 + signal);
+        }
+        if let Some(idle_timeout) = idle_timeout.filter(|t| activity.idle_for() >= *t) {
+            let idle_secs = idle_timeout.as_secs();
+            logging::event(Level::Info, Event::IdleShutdown { idle_secs });
+            invocation.finish(Some(0));
+            std::process::exit(0);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_upstream() {
+        assert_eq!(
+            Upstream::parse("http://10.0.0.5:8080"),
+            Ok(Upstream::Tcp("10.0.0.5:8080".to_string()))
+        );
+        assert_eq!(
+            Upstream::parse("http://backend.internal/api"),
+            Ok(Upstream::Tcp("backend.internal:80".to_string()))
+        );
+        assert_eq!(
+            Upstream::parse("tcp://[::1]:9000"),
+            Ok(Upstream::Tcp("[::1]:9000".to_string()))
+        );
+        assert_eq!(
+            Upstream::parse("unix:/run/app.sock"),
+            Ok(Upstream::Unix(PathBuf::from("/run/app.sock")))
+        );
+        assert!(Upstream::parse("tcp://localhost")
+            .unwrap_err()
+            .contains("missing port"));
+        assert!(Upstream::parse("https://example.com")
+            .unwrap_err()
+            .contains("not supported"));
+        assert!(Upstream::parse("unix:run/app.sock")
+            .unwrap_err()
+            .contains("absolute"));
+        assert!(Upstream::parse("localhost:8080").is_err());
+    }
+
+    #[test]
+    fn test_forward_to_unix_socket() {
+        let temp_dir = tempdir().unwrap();
+        let socket = temp_dir.path().join("app.sock");
+        let upstream_listener = UnixListener::bind(&socket).unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = upstream_listener.accept().unwrap();
+            let mut request = String::new();
+            stream.read_to_string(&mut request).unwrap();
+            stream
+                .write_all(format!("echo: {}", request).as_bytes())
+                .unwrap();
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (client, _) = listener.accept().unwrap();
+            forward(Stream::Tcp(client), &Upstream::Unix(socket)).unwrap();
+        });
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "echo: GET / HTTP/1.0\r\n\r\n");
+    }
+
+    #[test]
+    fn test_activity() {
+        let temp_dir = tempdir().unwrap();
+        let socket = temp_dir.path().join("app.sock");
+        let upstream_listener = UnixListener::bind(&socket).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let activity = Arc::new(Activity::new());
+        spawn_bridge(listener, Upstream::Unix(socket), Arc::clone(&activity));
+
+        let mut client = TcpStream::connect(address).unwrap();
+        let (mut server, _) = upstream_listener.accept().unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(activity.idle_for(), Duration::ZERO);
+
+        client.shutdown(Shutdown::Write).unwrap();
+        server.read_to_end(&mut Vec::new()).unwrap();
+        drop(server);
+        client.read_to_end(&mut Vec::new()).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert!(activity.idle_for() > Duration::ZERO);
+    }
+}
@@ -0,0 +1,288 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Language runtimes the adapter knows how to start without an `exec` line.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Runtime {
+    /// `main.py`, `app.py` or `server.py`, with `.venv` when present.
+    Python,
+    /// `main` from package.json, or `index.js`/`server.js`.
+    Node,
+    /// `config.ru` through rackup, or `app.rb`; via bundler with a Gemfile.
+    Ruby,
+    /// A compiled binary named after the app dir, as `go build` names it.
+    GoBinary,
+    /// Files in `public/`, or the app dir itself, served by the adapter.
+    Static,
+}
+
+impl Runtime {
+    const NAMES: [&'static str; 5] = ["python", "node", "ruby", "go-binary", "static"];
+
+    /// Works out how to start the app in `app_dir`. Interpreters are looked
+    /// up in `path_var`. `None` for `static`, which starts nothing.
+    pub fn preset(self, app_dir: &Path, path_var: &str) -> Option<Preset> {
+        let preset = match self {
+            Runtime::Python => python(app_dir, path_var),
+            Runtime::Node => node(app_dir, path_var),
+            Runtime::Ruby => ruby(app_dir, path_var),
+            Runtime::GoBinary => {
+                let name = app_dir.file_name().unwrap_or_default().to_string_lossy();
+                Preset {
+                    exec: format!("exec ./{}", shell_quote(&name)),
+                    mounts: vec![PathBuf::from("/etc/localtime")],
+                }
+            }
+            Runtime::Static => return None,
+        };
+        Some(preset)
+    }
+}
+
+impl crate::schema::JsonSchema for Runtime {
+    fn schema() -> Value {
+        json!({ "enum": Runtime::NAMES })
+    }
+}
+
+/// What a runtime contributes to the sandbox.
+#[derive(Debug, PartialEq)]
+pub struct Preset {
+    /// Shell command starting the app from the app dir.
+    pub exec: String,
+    /// Paths to mount read-only on top of the usual ones, when they exist.
+    pub mounts: Vec<PathBuf>,
+}
+
+fn python(app_dir: &Path, path_var: &str) -> Preset {
+    let entry = first_existing(app_dir, &["main.py", "app.py", "server.py"]).unwrap_or("main.py");
+    let venv = app_dir.join(".venv");
+    if !venv.join("bin/python3").exists() {
+        return Preset {
+            exec: format!("exec python3 {}", entry),
+            mounts: installation("python3", path_var).into_iter().collect(),
+        };
+    }
+    // The venv's interpreter is a symlink into the installation it was
+    // created from, recorded as `home` in pyvenv.cfg. That file is the
+    // app's to write, so the installation is only mounted when its bin dir
+    // is one of our own PATH entries.
+    let home = fs::read_to_string(venv.join("pyvenv.cfg"))
+        .ok()
+        .and_then(|cfg| {
+            cfg.lines()
+                .filter_map(|line| line.split_once('='))
+                .find(|(key, _)| key.trim() == "home")
+                .and_then(|(_, value)| fs::canonicalize(value.trim()).ok())
+        })
+        .filter(|bin| {
+            env::split_paths(path_var)
+                .filter_map(|dir| fs::canonicalize(dir).ok())
+                .any(|dir| dir == *bin)
+        });
+    Preset {
+        exec: format!("exec .venv/bin/python3 {}", entry),
+        mounts: home
+            .and_then(|bin| outside_system_dirs(bin.parent()?))
+            .into_iter()
+            .collect(),
+    }
+}
+
+fn node(app_dir: &Path, path_var: &str) -> Preset {
+    let main = fs::read_to_string(app_dir.join("package.json"))
+        .ok()
+        .and_then(|content| serde_json::from_str::<Value>(&content).ok())
+        .and_then(|package| package.get("main")?.as_str().map(String::from));
+    let entry = main.unwrap_or_else(|| {
+        first_existing(app_dir, &["index.js", "server.js"])
+            .unwrap_or("index.js")
+            .to_string()
+    });
+    let mut mounts: Vec<PathBuf> = installation("node", path_var).into_iter().collect();
+    // Mounted read-only on its own, even when the app dir is writable. A
+    // link is only followed within the app dir: anywhere else it would
+    // let the app pick what gets mounted.
+    let node_modules = fs::canonicalize(app_dir.join("node_modules"));
+    if let (Ok(node_modules), Ok(app_dir)) = (node_modules, fs::canonicalize(app_dir)) {
+        if node_modules.starts_with(&app_dir) {
+            mounts.push(node_modules);
+        }
+    }
+    Preset {
+        exec: format!("exec node {}", shell_quote(&entry)),
+        mounts,
+    }
+}
+
+fn ruby(app_dir: &Path, path_var: &str) -> Preset {
+    let bundle = if app_dir.join("Gemfile").exists() {
+        "bundle exec "
+    } else {
+        ""
+    };
+    let exec = if app_dir.join("config.ru").exists() {
+        format!("exec {}rackup --host 127.0.0.1 --port \"$PORT\"", bundle)
+    } else {
+        format!("exec {}ruby app.rb", bundle)
+    };
+    let mut mounts: Vec<PathBuf> = installation("ruby", path_var).into_iter().collect();
+    for var in ["GEM_HOME", "GEM_PATH"] {
+        if let Some(value) = env::var_os(var) {
+            mounts.extend(env::split_paths(&value).filter(|path| path.is_absolute()));
+        }
+    }
+    Preset { exec, mounts }
+}
+
+fn first_existing<'a>(dir: &Path, names: &[&'a str]) -> Option<&'a str> {
+    names.iter().copied().find(|name| dir.join(name).exists())
+}
+
+/// The installation prefix of `program` found in `path_var`, e.g.
+/// `~/.nvm/versions/node/v20` for its `bin/node`, unless the sandbox
+/// already has it.
+fn installation(program: &str, path_var: &str) -> Option<PathBuf> {
+    let binary = env::split_paths(path_var)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())?;
+    let binary = fs::canonicalize(binary).ok()?;
+    outside_system_dirs(binary.parent()?.parent()?)
+}
+
+/// `prefix`, unless it's below the directories every sandbox gets.
+fn outside_system_dirs(prefix: &Path) -> Option<PathBuf> {
+    let mounted = ["/usr", "/bin", "/lib"]
+        .iter()
+        .any(|dir| prefix.starts_with(dir));
+    (!mounted && prefix != Path::new("/")).then(|| prefix.to_path_buf())
+}
+
+fn shell_quote(word: &str) -> String {
+    if !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_alphanumeric() || "/_.-".contains(c))
+    {
+        return word.to_string();
+    }
+    format!("'{}'", word.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_presets() {
+        let temp_dir = tempdir().unwrap();
+        let app_dir = temp_dir.path().join("blog");
+        fs::create_dir(&app_dir).unwrap();
+
+        assert_eq!(
+            Runtime::Python.preset(&app_dir, "").unwrap().exec,
+            "exec python3 main.py"
+        );
+        assert_eq!(
+            Runtime::Ruby.preset(&app_dir, "").unwrap().exec,
+            "exec ruby app.rb"
+        );
+        assert_eq!(
+            Runtime::GoBinary.preset(&app_dir, "").unwrap().exec,
+            "exec ./blog"
+        );
+        assert_eq!(Runtime::Static.preset(&app_dir, ""), None);
+
+        fs::write(
+            app_dir.join("package.json"),
+            r#"{"main": "dist/my server.js"}"#,
+        )
+        .unwrap();
+        fs::create_dir(app_dir.join("node_modules")).unwrap();
+        fs::write(app_dir.join("Gemfile"), "").unwrap();
+        fs::write(app_dir.join("config.ru"), "").unwrap();
+        fs::write(app_dir.join("app.py"), "").unwrap();
+        fs::create_dir(app_dir.join("public")).unwrap();
+
+        let node = Runtime::Node.preset(&app_dir, "").unwrap();
+        assert_eq!(node.exec, "exec node 'dist/my server.js'");
+        assert_eq!(
+            node.mounts,
+            [fs::canonicalize(app_dir.join("node_modules")).unwrap()]
+        );
+        assert_eq!(
+            Runtime::Ruby.preset(&app_dir, "").unwrap().exec,
+            "exec bundle exec rackup --host 127.0.0.1 --port \"$PORT\""
+        );
+        assert_eq!(
+            Runtime::Python.preset(&app_dir, "").unwrap().exec,
+            "exec python3 app.py"
+        );
+    }
+
+    #[test]
+    fn test_interpreter_mounts() {
+        let temp_dir = tempdir().unwrap();
+        let prefix = temp_dir.path().join("opt/python3.12");
+        fs::create_dir_all(prefix.join("bin")).unwrap();
+        let python = prefix.join("bin/python3");
+        fs::write(&python, "").unwrap();
+        fs::set_permissions(&python, fs::Permissions::from_mode(0o755)).unwrap();
+        let path_var = format!("/nonexistent:{}", prefix.join("bin").display());
+
+        let app_dir = temp_dir.path().join("app");
+        fs::create_dir_all(app_dir.join(".venv/bin")).unwrap();
+        let preset = Runtime::Python.preset(&app_dir, &path_var).unwrap();
+        assert_eq!(preset.mounts, [fs::canonicalize(&prefix).unwrap()]);
+
+        fs::write(app_dir.join(".venv/bin/python3"), "").unwrap();
+        let cfg = format!(
+            "home = {}\ninclude-system-site-packages = false\n",
+            prefix.join("bin").display()
+        );
+        fs::write(app_dir.join(".venv/pyvenv.cfg"), cfg).unwrap();
+        let preset = Runtime::Python.preset(&app_dir, &path_var).unwrap();
+        assert_eq!(preset.exec, "exec .venv/bin/python3 main.py");
+        assert_eq!(preset.mounts, [fs::canonicalize(&prefix).unwrap()]);
+
+        // Nor is an installation the venv names but we don't use.
+        let preset = Runtime::Python.preset(&app_dir, "/nonexistent").unwrap();
+        assert!(preset.mounts.is_empty());
+        let cfg = format!("home = {}\n", temp_dir.path().display());
+        fs::write(app_dir.join(".venv/pyvenv.cfg"), cfg).unwrap();
+        let preset = Runtime::Python.preset(&app_dir, &path_var).unwrap();
+        assert!(preset.mounts.is_empty());
+
+        assert_eq!(outside_system_dirs(Path::new("/usr/local")), None);
+    }
+
+    #[test]
+    fn test_node_modules_stay_in_app_dir() {
+        let temp_dir = tempdir().unwrap();
+        let app_dir = temp_dir.path().join("app");
+        fs::create_dir_all(app_dir.join("vendor/modules")).unwrap();
+        fs::create_dir_all(temp_dir.path().join("secrets")).unwrap();
+
+        std::os::unix::fs::symlink("vendor/modules", app_dir.join("node_modules")).unwrap();
+        let preset = Runtime::Node.preset(&app_dir, "").unwrap();
+        assert_eq!(
+            preset.mounts,
+            [fs::canonicalize(app_dir.join("vendor/modules")).unwrap()]
+        );
+
+        fs::remove_file(app_dir.join("node_modules")).unwrap();
+        std::os::unix::fs::symlink(
+            temp_dir.path().join("secrets"),
+            app_dir.join("node_modules"),
+        )
+        .unwrap();
+        let preset = Runtime::Node.preset(&app_dir, "").unwrap();
+        assert!(preset.mounts.is_empty());
+    }
+}
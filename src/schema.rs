@@ -0,0 +1,241 @@
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// Describes a config type as a JSON Schema fragment. Structs get this from
+/// `config_struct!`, leaf types from the impls below.
+pub trait JsonSchema {
+    fn schema() -> Value;
+
+    /// Whether the key may be left out of the config entirely.
+    fn optional() -> bool {
+        false
+    }
+}
+
+impl JsonSchema for String {
+    fn schema() -> Value {
+        json!({ "type": "string" })
+    }
+}
+
+impl JsonSchema for PathBuf {
+    fn schema() -> Value {
+        json!({ "type": "string" })
+    }
+}
+
+impl JsonSchema for bool {
+    fn schema() -> Value {
+        json!({ "type": "boolean" })
+    }
+}
+
+impl JsonSchema for u16 {
+    fn schema() -> Value {
+        json!({ "type": "integer", "minimum": 0, "maximum": u16::MAX })
+    }
+}
+
+impl JsonSchema for u32 {
+    fn schema() -> Value {
+        json!({ "type": "integer", "minimum": 0, "maximum": u32::MAX })
+    }
+}
+
+impl JsonSchema for u64 {
+    fn schema() -> Value {
+        json!({ "type": "integer", "minimum": 0 })
+    }
+}
+
+impl<T: JsonSchema> JsonSchema for Option<T> {
+    /// `T`, or `null`, which serde reads as `None`.
+    fn schema() -> Value {
+        let schema = T::schema();
+        match schema.get("type").and_then(Value::as_str) {
+            Some(ty) => {
+                let mut schema = schema.clone();
+                schema["type"] = json!([ty, "null"]);
+                schema
+            }
+            None => json!({ "anyOf": [schema, { "type": "null" }] }),
+        }
+    }
+
+    fn optional() -> bool {
+        true
+    }
+}
+
+impl<T: JsonSchema> JsonSchema for Vec<T> {
+    fn schema() -> Value {
+        json!({ "type": "array", "items": T::schema() })
+    }
+}
+
+impl<T: JsonSchema> JsonSchema for BTreeMap<String, T> {
+    fn schema() -> Value {
+        json!({ "type": "object", "additionalProperties": T::schema() })
+    }
+}
+
+/// Joins doc comment lines into a single schema `description`.
+pub fn describe(mut schema: Value, doc: &[&str]) -> Value {
+    let description = doc
+        .iter()
+        .map(|line| line.trim())
+        .collect::<Vec<_>>()
+        .join(" ");
+    if !description.is_empty() {
+        schema["description"] = Value::String(description);
+    }
+    schema
+}
+
+/// The config key of a field, which for keywords such as `static` is a raw
+/// identifier: `r#static` reads the key `static`, as it does for serde.
+pub const fn key(field: &'static str) -> &'static str {
+    match field.as_bytes() {
+        [b'r', b'#', ..] => field.split_at(2).1,
+        _ => field,
+    }
+}
+
+/// Whether a field's serde attribute tokens include a bare `default`, as in
+/// `#[serde(default)]` or `#[serde(default = "path")]`. Strings such as
+/// `alias = "default_x"` are single literal tokens and don't match.
+macro_rules! serde_has_default {
+    () => {
+        false
+    };
+    (default $($rest:tt)*) => {
+        true
+    };
+    ($first:tt $($rest:tt)*) => {
+        serde_has_default!($($rest)*)
+    };
+}
+
+/// Declares a config struct, deriving `Deserialize` and generating its
+/// `JsonSchema` impl, its list of accepted keys and a per-field checker from
+/// the same definition, so the schema can't drift from what we parse. Doc
+/// comments on the fields become schema descriptions; fields that are
+/// `Option`s or carry `#[serde(default)]` are not required.
+macro_rules! config_struct {
+    (
+        $(#[doc = $doc:literal])*
+        $(#[derive($($derive:ident),*)])*
+        $(#[serde($($struct_serde:tt)*)])*
+        pub struct $name:ident {
+            $(
+                $(#[doc = $field_doc:literal])*
+                $(#[serde($($field_serde:tt)*)])*
+                pub $field:ident: $ty:ty,
+            )*
+        }
+    ) => {
+        $(#[doc = $doc])*
+        #[derive(serde::Deserialize, Debug, PartialEq, Clone)]
+        $(#[derive($($derive),*)])*
+        #[serde(deny_unknown_fields)]
+        $(#[serde($($struct_serde)*)])*
+        pub struct $name {
+            $(
+                $(#[doc = $field_doc])*
+                $(#[serde($($field_serde)*)])*
+                pub $field: $ty,
+            )*
+        }
+
+        impl $name {
+            /// Keys accepted in this section of the config.
+            #[allow(dead_code)]
+            pub const FIELDS: &'static [&'static str] = &[$($crate::schema::key(stringify!($field))),*];
+
+            /// Type-checks each present key on its own and reports missing
+            /// required keys, so every problem is reported at once.
+            #[allow(dead_code)]
+            pub fn field_errors(
+                map: &serde_json::Map<String, serde_json::Value>,
+            ) -> Vec<(&'static str, String)> {
+                let mut errors = Vec::new();
+                $(
+                    match map.get($crate::schema::key(stringify!($field))) {
+                        Some(value) => {
+                            if let Err(e) = serde_json::from_value::<$ty>(value.clone()) {
+                                errors.push(($crate::schema::key(stringify!($field)), e.to_string()));
+                            }
+                        }
+                        None => {
+                            let has_default = serde_has_default!($($($field_serde)*)*);
+                            if !has_default && !<$ty as $crate::schema::JsonSchema>::optional() {
+                                errors.push((
+                                    $crate::schema::key(stringify!($field)),
+                                    format!("missing field `{}`", $crate::schema::key(stringify!($field))),
+                                ));
+                            }
+                        }
+                    }
+                )*
+                errors
+            }
+        }
+
+        impl $crate::schema::JsonSchema for $name {
+            fn schema() -> serde_json::Value {
+                let mut properties = serde_json::Map::new();
+                #[allow(unused_mut)]
+                let mut required: Vec<&str> = Vec::new();
+                $(
+                    properties.insert(
+                        $crate::schema::key(stringify!($field)).to_string(),
+                        $crate::schema::describe(
+                            <$ty as $crate::schema::JsonSchema>::schema(),
+                            &[$($field_doc),*],
+                        ),
+                    );
+                    let has_default = serde_has_default!($($($field_serde)*)*);
+                    if !has_default && !<$ty as $crate::schema::JsonSchema>::optional() {
+                        required.push($crate::schema::key(stringify!($field)));
+                    }
+                )*
+                $crate::schema::describe(
+                    serde_json::json!({
+                        "type": "object",
+                        "properties": properties,
+                        "required": required,
+                        "additionalProperties": false,
+                    }),
+                    &[$($doc),*],
+                )
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    config_struct! {
+        pub struct Example {
+            #[serde(default)]
+            pub defaulted: u32,
+            #[serde(alias = "default_name")]
+            pub renamed: String,
+            pub optional: Option<bool>,
+        }
+    }
+
+    #[test]
+    fn test_required_fields() {
+        use super::JsonSchema;
+        let schema = Example::schema();
+        assert_eq!(schema["required"], serde_json::json!(["renamed"]));
+        assert_eq!(
+            schema["properties"]["optional"]["type"],
+            serde_json::json!(["boolean", "null"])
+        );
+        let errors = Example::field_errors(&serde_json::Map::new());
+        assert_eq!(errors, [("renamed", "missing field `renamed`".to_string())]);
+    }
+}
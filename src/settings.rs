@@ -0,0 +1,102 @@
+use crate::environment::EnvPolicy;
+use crate::history::HistorySettings;
+use crate::jsonc;
+use crate::logging::LogSettings;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+config_struct! {
+    /// Adapter-wide settings, shared by every app. Read from the first of
+    /// `$SMALLWEB_ADAPTER_CONFIG`, `~/.config/smallweb-adapter/config.json(c)`
+    /// and `/etc/smallweb-adapter/config.json(c)` that exists.
+    #[derive(Default)]
+    pub struct Settings {
+        /// Absolute path of the real deno binary. When set, deno is executed
+        /// from here instead of being searched for in `PATH`. Overridden by
+        /// `$SMALLWEB_ADAPTER_DENO`.
+        pub deno: Option<PathBuf>,
+        /// How to rebuild `PATH` when Smallweb strips the environment.
+        #[serde(default)]
+        pub env: EnvPolicy,
+        /// Level and destination of the adapter's own log.
+        #[serde(default)]
+        pub log: LogSettings,
+        /// Where the invocation history is recorded.
+        #[serde(default)]
+        pub history: HistorySettings,
+    }
+}
+
+/// Env var naming an explicit settings file.
+pub const CONFIG_ENV: &str = "SMALLWEB_ADAPTER_CONFIG";
+/// Env var naming the real deno binary, overriding `deno` in the settings file.
+pub const DENO_ENV: &str = "SMALLWEB_ADAPTER_DENO";
+
+impl Settings {
+    /// Loads the settings file (if any) and applies env var overrides.
+    pub fn load() -> Result<Settings, String> {
+        let mut settings = match settings_path() {
+            Some(path) => {
+                debug_log!("loading settings from {}", path.display());
+                let content = fs::read_to_string(&path)
+                    .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+                Settings::parse(&content)
+                    .map_err(|e| format!("invalid {}: {}", path.display(), e))?
+            }
+            None => Settings::default(),
+        };
+        if let Some(deno) = env::var_os(DENO_ENV).filter(|v| !v.is_empty()) {
+            settings.deno = Some(PathBuf::from(deno));
+        }
+        Ok(settings)
+    }
+
+    pub fn parse(content: &str) -> Result<Settings, String> {
+        let json = jsonc::strip(content).map_err(|e| e.to_string())?;
+        serde_json::from_str(&json).map_err(|e| e.to_string())
+    }
+}
+
+fn settings_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os(CONFIG_ENV).filter(|v| !v.is_empty()) {
+        return Some(PathBuf::from(path));
+    }
+    let user_dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|dir| dir.join("smallweb-adapter"));
+    user_dir
+        .into_iter()
+        .chain([PathBuf::from("/etc/smallweb-adapter")])
+        .flat_map(|dir| ["config.json", "config.jsonc"].map(|name| dir.join(name)))
+        .find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_settings() {
+        let settings = Settings::parse(
+            r#"{
+  // deno lives next to the adapter
+  "deno": "/opt/deno/bin/deno",
+}"#,
+        )
+        .unwrap();
+        assert_eq!(settings.deno, Some(PathBuf::from("/opt/deno/bin/deno")));
+        assert_eq!(settings.env, EnvPolicy::default());
+
+        let settings = Settings::parse(
+            r#"{"env": {"default_path": "/usr/bin:/bin", "sources": ["/etc/environment"]}}"#,
+        )
+        .unwrap();
+        assert_eq!(settings.env.default_path.as_deref(), Some("/usr/bin:/bin"));
+        assert_eq!(Settings::parse("{}").unwrap(), Settings::default());
+        assert!(Settings::parse(r#"{"denno": "/usr/bin/deno"}"#)
+            .unwrap_err()
+            .contains("unknown field `denno`"));
+    }
+}
@@ -0,0 +1,419 @@
+use crate::linux;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::env;
+use std::net::TcpStream;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Makes the adapter run `sidecars` and then the app inside the sandbox.
+pub const RUN_FLAG: &str = "--smallweb-adapter-sidecars";
+/// How long a sidecar gets to listen on its `ready_port` by default.
+const DEFAULT_READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+config_struct! {
+    /// A process started next to the app, in the same sandbox.
+    #[derive(Serialize)]
+    pub struct SidecarConfig {
+        /// Name used in messages, unique among the sidecars.
+        pub name: String,
+        /// Shell command starting it, run from the app dir like `exec`.
+        pub exec: String,
+        /// Port on 127.0.0.1 it listens on. Later sidecars and the app wait
+        /// until it accepts connections; without one they start right away.
+        pub ready_port: Option<u16>,
+        /// Seconds to wait for `ready_port` before stopping everything.
+        /// Defaults to 30.
+        pub ready_timeout: Option<u64>,
+        /// Whether the app and the other sidecars are stopped when this one
+        /// exits. Defaults to true; otherwise the app carries on without it.
+        pub critical: Option<bool>,
+    }
+}
+
+/// Checks that every sidecar has a name of its own.
+pub fn validate(sidecars: &[SidecarConfig]) -> Result<(), String> {
+    let mut names = BTreeSet::new();
+    for sidecar in sidecars {
+        if sidecar.name.is_empty() {
+            return Err("sidecar names can't be empty".to_string());
+        }
+        if !names.insert(sidecar.name.as_str()) {
+            return Err(format!("sidecar name `{}` is used twice", sidecar.name));
+        }
+    }
+    Ok(())
+}
+
+/// A process started by `run`. `name` is `None` for the app itself.
+struct Process {
+    name: Option<String>,
+    child: Child,
+    critical: bool,
+}
+
+/// What `run` is looking after.
+struct Processes {
+    running: Vec<Process>,
+    shutdown_timeout: Duration,
+}
+
+impl Processes {
+    /// Starts `command` in a process group of its own, so it can be stopped
+    /// with everything it started.
+    fn spawn(&mut self, name: Option<&str>, critical: bool, command: &mut Command) {
+        let label = name.map_or_else(|| "app".to_string(), |name| format!("sidecar `{}`", name));
+        match command.process_group(0).spawn() {
+            Ok(child) => self.running.push(Process {
+                name: name.map(String::from),
+                child,
+                critical,
+            }),
+            Err(e) => {
+                eprintln!("error: failed to start {}: {}", label, e);
+                self.stop(true, 1);
+            }
+        }
+    }
+
+    fn is_running(&self, name: &str) -> bool {
+        self.running
+            .iter()
+            .any(|process| process.name.as_deref() == Some(name))
+    }
+
+    /// Handles a pending signal and processes that exited: when the app or
+    /// a critical sidecar is gone, everything is stopped.
+    fn check(&mut self) {
+        if let Some(signal) = linux::take_pending_signal() {
+            // The adapter signals every process group in the sandbox, ours
+            // and our children's alike.
+            self.stop(false, 128 + signal);
+        }
+        let mut index = 0;
+        while index < self.running.len() {
+            let status = match self.running[index].child.try_wait() {
+                Ok(Some(status)) => status,
+                Ok(None) => {
+                    index += 1;
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!("error: failed to check process status: {}", e);
+                    self.stop(true, 1);
+                }
+            };
+            let process = self.running.remove(index);
+            match process.name {
+                None => self.stop(true, exit_code(status)),
+                Some(name) if process.critical => {
+                    eprintln!("sidecar `{}` exited ({}), stopping the app", name, status);
+                    self.stop(true, 1);
+                }
+                Some(name) => eprintln!(
+                    "sidecar `{}` exited ({}), carrying on without it",
+                    name, status
+                ),
+            }
+        }
+    }
+
+    /// Waits up to `shutdown_timeout` for every process to exit, after
+    /// SIGTERMing them if `terminate`, then kills what is left and exits with
+    /// `code`.
+    fn stop(&mut self, terminate: bool, code: i32) -> ! {
+        if terminate {
+            self.signal(libc::SIGTERM);
+        }
+        let deadline = Instant::now() + self.shutdown_timeout;
+        while Instant::now() < deadline {
+            self.running
+                .retain_mut(|process| matches!(process.child.try_wait(), Ok(None)));
+            if self.running.is_empty() {
+                std::process::exit(code);
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        self.signal(libc::SIGKILL);
+        for process in &mut self.running {
+            let _ = process.child.wait();
+        }
+        std::process::exit(code);
+    }
+
+    fn signal(&self, signal: i32) {
+        for process in &self.running {
+            // SAFETY: killpg has no memory-safety preconditions.
+            unsafe { libc::killpg(process.child.id() as libc::pid_t, signal) };
+        }
+    }
+}
+
+fn exit_code(status: ExitStatus) -> i32 {
+    status
+        .code()
+        .unwrap_or_else(|| 128 + status.signal().unwrap_or(0))
+}
+
+/// Starts `sidecars` in order, each once the one before is ready, then the
+/// app as `main`, which inherits our stdin. Runs inside the sandbox until the
+/// app or a critical sidecar exits, stopping the rest, and exits with the
+/// app's code or 1. Never returns.
+pub fn run(sidecars: Vec<SidecarConfig>, shutdown_timeout: Duration, main: &[String]) -> ! {
+    linux::install_signal_handlers();
+    let mut processes = Processes {
+        running: Vec::new(),
+        shutdown_timeout,
+    };
+    // The passed listener is the app's alone; sidecars must not inherit it.
+    let activated = env::var_os("LISTEN_FDS").is_some();
+    if activated {
+        if let Err(e) = linux::set_close_on_exec(linux::LISTEN_FDS_START) {
+            eprintln!(
+                "error: failed to keep the passed socket from sidecars: {}",
+                e
+            );
+            std::process::exit(1);
+        }
+    }
+    for sidecar in &sidecars {
+        let mut command = Command::new("/bin/sh");
+        command.arg("-c").arg(&sidecar.exec).stdin(Stdio::null());
+        // Only the app may claim the passed socket or report readiness.
+        for var in ["LISTEN_FDS", "LISTEN_PID", "NOTIFY_SOCKET"] {
+            command.env_remove(var);
+        }
+        processes.spawn(
+            Some(&sidecar.name),
+            sidecar.critical.unwrap_or(true),
+            &mut command,
+        );
+        if let Some(port) = sidecar.ready_port {
+            let timeout = sidecar
+                .ready_timeout
+                .map_or(DEFAULT_READY_TIMEOUT, Duration::from_secs);
+            let deadline = Instant::now() + timeout;
+            while processes.is_running(&sidecar.name)
+                && TcpStream::connect(("127.0.0.1", port)).is_err()
+            {
+                processes.check();
+                if Instant::now() >= deadline {
+                    eprintln!(
+                        "error: sidecar `{}` didn't listen on port {} within {}s",
+                        sidecar.name,
+                        port,
+                        timeout.as_secs()
+                    );
+                    processes.stop(true, 1);
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        }
+    }
+
+    let mut command = Command::new(&main[0]);
+    command.args(&main[1..]);
+    if activated {
+        linux::pass_listen_fd(&mut command, linux::LISTEN_FDS_START);
+    }
+    processes.spawn(None, true, &mut command);
+    loop {
+        processes.check();
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::os::fd::AsRawFd;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    /// Set in the copy of the test binary that plays the sidecar runner, to
+    /// the sidecars as JSON. The app's shell script is in `$APP`.
+    const SIDECARS_ENV: &str = "SMALLWEB_ADAPTER_TEST_SIDECARS";
+
+    /// Runs `test` again in a process of its own, which calls `run` with
+    /// `sidecars` and `sh -c app`, optionally passing `listener` as fd 3.
+    /// The scripts share `$LOG` in `dir`. Returns the exit code.
+    fn run_sidecars(
+        test: &str,
+        dir: &Path,
+        sidecars: &str,
+        app: &str,
+        listener: Option<&TcpListener>,
+    ) -> Option<i32> {
+        let mut command = Command::new(env::current_exe().unwrap());
+        command
+            .args([test, "--exact", "--nocapture"])
+            .env(SIDECARS_ENV, sidecars)
+            .env("APP", app)
+            .env("LOG", dir.join("log"))
+            .current_dir(dir)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        if let Some(listener) = listener {
+            linux::pass_listen_fd(&mut command, listener.as_raw_fd());
+        }
+        command.status().unwrap().code()
+    }
+
+    /// The runner's side of `run_sidecars`, when this process is the copy.
+    fn play_runner() {
+        let Ok(sidecars) = env::var(SIDECARS_ENV) else {
+            return;
+        };
+        let app = env::var("APP").unwrap();
+        let main = ["/bin/sh".to_string(), "-c".to_string(), app];
+        run(
+            serde_json::from_str(&sidecars).unwrap(),
+            Duration::from_secs(2),
+            &main,
+        )
+    }
+
+    fn log(dir: &Path) -> Vec<String> {
+        let content = fs::read_to_string(dir.join("log")).unwrap_or_default();
+        content.lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_start_order_waits_for_ready_port() {
+        play_runner();
+        let temp_dir = tempdir().unwrap();
+        // Nothing listens until the test binds the port itself, after `db`
+        // has been started.
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let sidecars = format!(
+            r#"[{{"name":"db","exec":"echo db >> \"$LOG\"; exec sleep 300","ready_port":{}}},
+                {{"name":"worker","exec":"echo worker >> \"$LOG\"; exec sleep 300","critical":false}}]"#,
+            port
+        );
+        let dir = temp_dir.path().to_path_buf();
+        let binder = thread::spawn(move || {
+            while log(&dir).is_empty() {
+                thread::sleep(Duration::from_millis(10));
+            }
+            thread::sleep(Duration::from_millis(300));
+            let mut log = fs::OpenOptions::new()
+                .append(true)
+                .open(dir.join("log"))
+                .unwrap();
+            log.write_all(b"listening\n").unwrap();
+            TcpListener::bind(("127.0.0.1", port)).unwrap()
+        });
+        let code = run_sidecars(
+            "sidecar::tests::test_start_order_waits_for_ready_port",
+            temp_dir.path(),
+            &sidecars,
+            "while [ $(wc -l < \"$LOG\") -lt 3 ]; do sleep 0.05; done; echo app >> \"$LOG\"; exit 7",
+            None,
+        );
+        binder.join().unwrap();
+        assert_eq!(code, Some(7));
+        assert_eq!(log(temp_dir.path()), ["db", "listening", "worker", "app"]);
+    }
+
+    #[test]
+    fn test_ready_timeout() {
+        play_runner();
+        let temp_dir = tempdir().unwrap();
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let sidecars = format!(
+            r#"[{{"name":"db","exec":"exec sleep 300","ready_port":{},"ready_timeout":1}}]"#,
+            port
+        );
+        let start = Instant::now();
+        let code = run_sidecars(
+            "sidecar::tests::test_ready_timeout",
+            temp_dir.path(),
+            &sidecars,
+            "echo app >> \"$LOG\"",
+            None,
+        );
+        assert_eq!(code, Some(1));
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(log(temp_dir.path()).is_empty());
+    }
+
+    #[test]
+    fn test_critical_sidecar_exit_stops_app() {
+        play_runner();
+        let temp_dir = tempdir().unwrap();
+        let sidecars =
+            r#"[{"name":"worker","exec":"while [ ! -s \"$LOG\" ]; do sleep 0.05; done; exit 2"}]"#;
+        let start = Instant::now();
+        let code = run_sidecars(
+            "sidecar::tests::test_critical_sidecar_exit_stops_app",
+            temp_dir.path(),
+            sidecars,
+            "sleep 300 & echo $! >> \"$LOG\"; wait",
+            None,
+        );
+        assert_eq!(code, Some(1));
+        assert!(start.elapsed() < Duration::from_secs(10));
+        let pid: u32 = log(temp_dir.path())[0].parse().unwrap();
+        assert!(!linux::is_alive(pid), "app process {} survived", pid);
+    }
+
+    #[test]
+    fn test_passed_socket_reaches_only_the_app() {
+        play_runner();
+        let temp_dir = tempdir().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let sidecars = r#"[{"name":"worker","exec":"[ -e /proc/self/fd/3 ] && echo worker fd 3 >> \"$LOG\"; echo worker >> \"$LOG\"; exec sleep 300"}]"#;
+        let code = run_sidecars(
+            "sidecar::tests::test_passed_socket_reaches_only_the_app",
+            temp_dir.path(),
+            sidecars,
+            "while ! grep -qx worker \"$LOG\"; do sleep 0.05; done; [ -S /proc/self/fd/3 ] && echo app fd 3 >> \"$LOG\"",
+            Some(&listener),
+        );
+        assert_eq!(code, Some(0));
+        assert_eq!(log(temp_dir.path()), ["worker", "app fd 3"]);
+    }
+
+    fn sidecar(name: &str) -> SidecarConfig {
+        SidecarConfig {
+            name: name.to_string(),
+            exec: "redis-server".to_string(),
+            ready_port: Some(6379),
+            ready_timeout: None,
+            critical: None,
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate(&[sidecar("redis"), sidecar("worker")]).is_ok());
+        assert_eq!(
+            validate(&[sidecar("redis"), sidecar("redis")]).unwrap_err(),
+            "sidecar name `redis` is used twice"
+        );
+        assert!(validate(&[sidecar("")]).is_err());
+    }
+
+    #[test]
+    fn test_spec_round_trip() {
+        let sidecars = vec![sidecar("redis")];
+        let spec = serde_json::to_string(&sidecars).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Vec<SidecarConfig>>(&spec).unwrap(),
+            sidecars
+        );
+    }
+}
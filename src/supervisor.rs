@@ -0,0 +1,901 @@
+use crate::applog::{Capture, RotatingLog};
+use crate::config::SmallwebConfig;
+use crate::diagnosis::{self, ExitReason};
+use crate::history::Invocation;
+use crate::linux;
+use crate::logging::{self, Event, Level};
+use crate::proxy::{self, Activity, Upstream};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::env;
+use std::ffi::{CString, OsString};
+use std::fs;
+use std::io::{self, Write};
+use std::net::TcpListener;
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::net::UnixDatagram;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait for the app to start listening on its port.
+pub const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+/// Once the app has stayed up this long, a crash no longer counts towards
+/// `max_retries` and the backoff starts over.
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+/// When the app is restarted after exiting.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartMode {
+    Never,
+    /// After a non-zero exit code or a signal.
+    OnFailure,
+    /// After any exit.
+    Always,
+}
+
+impl RestartMode {
+    const NAMES: [&'static str; 3] = ["never", "on-failure", "always"];
+}
+
+impl crate::schema::JsonSchema for RestartMode {
+    fn schema() -> Value {
+        json!({ "enum": RestartMode::NAMES })
+    }
+}
+
+/// How the adapter tells that the app is up.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Readiness {
+    /// `$PORT`, or the app's `socket`, accepts connections.
+    Port,
+    /// The app sent `READY=1` to `$NOTIFY_SOCKET`, as under systemd.
+    Notify,
+}
+
+impl Readiness {
+    const NAMES: [&'static str; 2] = ["port", "notify"];
+}
+
+impl crate::schema::JsonSchema for Readiness {
+    fn schema() -> Value {
+        json!({ "enum": Readiness::NAMES })
+    }
+}
+
+config_struct! {
+    /// Restarting the app in place, on the same port and under the same
+    /// adapter process, when it exits after READY.
+    pub struct RestartPolicy {
+        /// `never`, `on-failure` or `always`. Defaults to `on-failure`.
+        pub mode: Option<RestartMode>,
+        /// Restarts allowed in a row before the adapter gives up and exits
+        /// with the app's status. Defaults to 5.
+        pub max_retries: Option<u32>,
+        /// Delay before the first restart in milliseconds, doubled for each
+        /// further one. Defaults to 1000.
+        pub backoff_ms: Option<u64>,
+        /// Upper bound for the delay between restarts. Defaults to 30000.
+        pub max_backoff_ms: Option<u64>,
+    }
+}
+
+impl RestartPolicy {
+    fn should_restart(&self, status: ExitStatus) -> bool {
+        match self.mode.unwrap_or(RestartMode::OnFailure) {
+            RestartMode::Never => false,
+            RestartMode::OnFailure => !status.success(),
+            RestartMode::Always => true,
+        }
+    }
+
+    fn max_retries(&self) -> u32 {
+        self.max_retries.unwrap_or(5)
+    }
+
+    /// Delay before restart number `attempt`, counting from 1.
+    fn delay(&self, attempt: u32) -> Duration {
+        let base = self.backoff_ms.unwrap_or(1000);
+        let max = self.max_backoff_ms.unwrap_or(30_000);
+        let factor = 1u64
+            .checked_shl(attempt.saturating_sub(1))
+            .unwrap_or(u64::MAX);
+        Duration::from_millis(base.saturating_mul(factor).min(max))
+    }
+}
+
+/// A private host directory mounted into the sandbox as the directory of the
+/// app's Unix socket, so the adapter can reach the socket from outside.
+pub struct SocketDir {
+    host: PathBuf,
+    socket: PathBuf,
+}
+
+impl SocketDir {
+    /// Creates the host side for `socket`, the socket's path in the sandbox.
+    pub fn create(socket: &Path) -> io::Result<SocketDir> {
+        Ok(SocketDir {
+            host: private_dir("")?,
+            socket: socket.to_path_buf(),
+        })
+    }
+
+    /// Creates a directory for the socket of the adapter's own forwarder
+    /// inside the sandbox, mounted at the same path.
+    pub fn for_forwarder() -> io::Result<SocketDir> {
+        let host = private_dir("-forward")?;
+        Ok(SocketDir {
+            socket: host.join("forward.sock"),
+            host,
+        })
+    }
+
+    pub fn bwrap_args(&self) -> [String; 3] {
+        let sandbox_dir = self.socket.parent().unwrap_or(Path::new("/"));
+        [
+            "--bind".to_string(),
+            self.host.display().to_string(),
+            sandbox_dir.display().to_string(),
+        ]
+    }
+
+    /// The socket as seen from inside the sandbox.
+    pub fn socket(&self) -> &Path {
+        &self.socket
+    }
+
+    /// The socket as seen from outside the sandbox.
+    pub fn host_socket(&self) -> PathBuf {
+        self.host.join(self.socket.file_name().unwrap_or_default())
+    }
+
+    fn remove(&self) {
+        remove_dir(&self.host);
+    }
+}
+
+/// The `NOTIFY_SOCKET` the app reports its state to, in a private host
+/// directory mounted into the sandbox at the same path.
+pub struct NotifySocket {
+    dir: PathBuf,
+    socket: UnixDatagram,
+}
+
+impl NotifySocket {
+    pub fn create() -> io::Result<NotifySocket> {
+        let dir = private_dir("-notify")?;
+        let socket = UnixDatagram::bind(dir.join("notify.sock"))?;
+        socket.set_nonblocking(true)?;
+        Ok(NotifySocket { dir, socket })
+    }
+
+    pub fn bwrap_args(&self) -> [String; 3] {
+        let dir = self.dir.display().to_string();
+        ["--bind".to_string(), dir.clone(), dir]
+    }
+
+    /// The value for `$NOTIFY_SOCKET`.
+    pub fn path(&self) -> PathBuf {
+        self.dir.join("notify.sock")
+    }
+
+    /// Handles the messages received so far, returning whether one of them
+    /// reported READY=1.
+    fn poll(&self) -> bool {
+        let mut ready = false;
+        let mut buf = [0; 4096];
+        while let Ok(len) = self.socket.recv(&mut buf) {
+            ready |= handle_notify_message(&String::from_utf8_lossy(&buf[..len]));
+        }
+        ready
+    }
+
+    fn remove(&self) {
+        remove_dir(&self.dir);
+    }
+}
+
+/// Logs the `STATUS=` of a notify message, a newline-separated list of
+/// `KEY=VALUE` assignments, and returns whether it contains READY=1.
+fn handle_notify_message(message: &str) -> bool {
+    let mut ready = false;
+    for (key, value) in message.lines().filter_map(|line| line.split_once('=')) {
+        match key {
+            "READY" => ready |= value == "1",
+            "STATUS" => info_log!("app status: {}", value),
+            _ => trace_log!("ignoring notify message {}={}", key, value),
+        }
+    }
+    ready
+}
+
+/// Creates a directory accessible to us only, named
+/// `smallweb-adapter-<pid><suffix>-XXXXXX` with a random tail so nobody can
+/// claim the name first, in `$XDG_RUNTIME_DIR` or else the temp dir.
+fn private_dir(suffix: &str) -> io::Result<PathBuf> {
+    let parent = env::var_os("XDG_RUNTIME_DIR")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(env::temp_dir);
+    let template = parent.join(format!(
+        "smallweb-adapter-{}{}-XXXXXX",
+        std::process::id(),
+        suffix
+    ));
+    let template = CString::new(template.into_os_string().into_vec())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut template = template.into_bytes_with_nul();
+    // SAFETY: template is a writable, NUL-terminated buffer that mkdtemp
+    // only fills in; it creates the directory with mode 0700.
+    if unsafe { libc::mkdtemp(template.as_mut_ptr().cast()) }.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    template.pop();
+    Ok(PathBuf::from(OsString::from_vec(template)))
+}
+
+fn remove_dir(dir: &Path) {
+    if let Err(e) = fs::remove_dir_all(dir) {
+        debug_log!("failed to remove {}: {}", dir.display(), e);
+    }
+}
+
+/// How connections to `$PORT` reach the app.
+pub enum Inbound {
+    /// The app binds the port itself.
+    Port,
+    /// The app listens on a Unix socket, bridged to the port once it's up.
+    Socket(SocketDir),
+    /// The adapter bound the port and passes the listener to the app.
+    Activated(TcpListener),
+    /// The app listens on a fixed `port` in a network namespace of its own,
+    /// reached through a forwarder inside the sandbox listening in
+    /// `socket_dir`.
+    Remapped { socket_dir: SocketDir, port: u16 },
+}
+
+impl Inbound {
+    /// The directory of the Unix socket the port is bridged to, if any.
+    fn socket_dir(&self) -> Option<&SocketDir> {
+        match self {
+            Inbound::Socket(socket_dir) | Inbound::Remapped { socket_dir, .. } => Some(socket_dir),
+            Inbound::Port | Inbound::Activated(_) => None,
+        }
+    }
+}
+
+/// Where Smallweb reaches the app and how it tells us that it's up.
+pub struct Listen {
+    pub port: u16,
+    pub inbound: Inbound,
+    /// Set when readiness is reported over `NOTIFY_SOCKET`.
+    pub notify: Option<NotifySocket>,
+}
+
+impl Listen {
+    fn remove_dirs(&self) {
+        if let Some(socket_dir) = self.inbound.socket_dir() {
+            socket_dir.remove();
+        }
+        if let Some(notify) = &self.notify {
+            notify.remove();
+        }
+    }
+}
+
+/// Why the app didn't become ready.
+enum StartupFailure {
+    Exited(ExitStatus),
+    TimedOut,
+}
+
+/// A running sandbox and everything that has to be wound down with it.
+struct Sandbox {
+    child: Child,
+    capture: Option<Capture>,
+    app_log: Option<Arc<Mutex<RotatingLog>>>,
+    startup_timeout: Duration,
+    shutdown_timeout: Duration,
+    /// Stop the app after this long without connections.
+    idle_timeout: Option<Duration>,
+    activity: Arc<Activity>,
+    invocation: Option<Invocation>,
+    /// Last lines of stderr from the most recently drained capture.
+    stderr_tail: Vec<String>,
+    /// OOM kills in our cgroup when the app was started.
+    oom_kills: Option<u64>,
+    listen: Listen,
+}
+
+impl Sandbox {
+    /// Exits the adapter with `code`, taking down whatever is left of the
+    /// sandbox and letting captured output drain first.
+    fn exit(&mut self, code: i32) -> ! {
+        self.kill_tree();
+        self.drain_capture();
+        self.listen.remove_dirs();
+        if let Some(invocation) = self.invocation.take() {
+            invocation.finish(Some(code));
+        }
+        std::process::exit(code);
+    }
+
+    fn drain_capture(&mut self) {
+        if let Some(capture) = self.capture.take() {
+            self.stderr_tail = capture.finish(Duration::from_secs(1));
+        }
+    }
+
+    /// Works out why the app ended, logs it and prints a diagnostic summary
+    /// unless it exited cleanly after READY.
+    fn report_exit(&mut self, status: ExitStatus, early: bool) -> ExitReason {
+        self.drain_capture();
+        let oom = linux::oom_kill_count()
+            .zip(self.oom_kills)
+            .is_some_and(|(now, before)| now > before);
+        let reason = ExitReason::classify(status, &self.stderr_tail, oom);
+        let level = if status.success() {
+            Level::Info
+        } else {
+            Level::Error
+        };
+        logging::event(
+            level,
+            Event::ChildExit {
+                code: status.code(),
+                signal: status.signal(),
+                early,
+                reason: reason.kind(),
+            },
+        );
+        if let Some(invocation) = self.invocation.as_mut() {
+            invocation.set_signal(status.signal());
+        }
+        if early || !status.success() {
+            eprintln!("{}", diagnosis::summary(&reason, early, &self.stderr_tail));
+        }
+        reason
+    }
+
+    /// Restarts the app from `command`.
+    fn respawn(&mut self, command: &mut Command, shell_script: Option<&str>) -> io::Result<()> {
+        // Most servers refuse to bind over a stale socket file.
+        if let Some(socket_dir) = self.listen.inbound.socket_dir() {
+            let _ = fs::remove_file(socket_dir.host_socket());
+        }
+        // Only a READY=1 from the new app counts.
+        if let Some(notify) = &self.listen.notify {
+            notify.poll();
+        }
+        self.child = command.spawn()?;
+        logging::event(
+            Level::Info,
+            Event::SandboxSpawned {
+                child_pid: self.child.id(),
+            },
+        );
+        self.attach(shell_script)
+    }
+
+    /// Starts capturing the child's output and feeds it `shell_script`.
+    fn attach(&mut self, shell_script: Option<&str>) -> io::Result<()> {
+        self.oom_kills = linux::oom_kill_count();
+        let (stdout, stderr) = (self.child.stdout.take(), self.child.stderr.take());
+        self.capture = Some(Capture::start(self.app_log.clone(), stdout, stderr));
+
+        if let Some(script) = shell_script {
+            if let Some(mut stdin) = self.child.stdin.take() {
+                if let Err(e) = stdin.write_all(script.as_bytes()) {
+                    self.kill_tree();
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Polls until the port, or the app's socket, is listening or the app
+    /// reported READY=1, handling signals meanwhile. A passed listener is
+    /// ready from the start.
+    fn wait_for_port(&mut self) -> Result<Duration, StartupFailure> {
+        let start = Instant::now();
+        loop {
+            self.handle_signals();
+            if let Some(status) = self.try_wait() {
+                return Err(StartupFailure::Exited(status));
+            }
+            let listening = match (&self.listen.notify, &self.listen.inbound) {
+                (Some(notify), _) => notify.poll(),
+                (None, Inbound::Port) => linux::is_port_listening(self.listen.port),
+                (None, Inbound::Socket(socket_dir)) => {
+                    linux::is_socket_listening(&socket_dir.host_socket())
+                }
+                (None, Inbound::Activated(_)) => true,
+                (None, Inbound::Remapped { socket_dir, port }) => {
+                    linux::is_port_listening_in_sandbox(self.child.id(), *port)
+                        && linux::is_socket_listening(&socket_dir.host_socket())
+                }
+            };
+            if listening {
+                return Ok(start.elapsed());
+            }
+            if start.elapsed() > self.startup_timeout {
+                return Err(StartupFailure::TimedOut);
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    /// Waits for the app to exit, handling signals, logging its status
+    /// messages and stopping it once idle meanwhile.
+    fn wait(&mut self) -> ExitStatus {
+        loop {
+            self.handle_signals();
+            if let Some(notify) = &self.listen.notify {
+                notify.poll();
+            }
+            self.check_idle();
+            match self.try_wait() {
+                Some(status) => return status,
+                None => thread::sleep(Duration::from_millis(100)),
+            }
+        }
+    }
+
+    fn try_wait(&mut self) -> Option<ExitStatus> {
+        match self.child.try_wait() {
+            Ok(status) => status,
+            Err(e) => {
+                eprintln!("error: failed to check child process status: {}", e);
+                self.exit(1);
+            }
+        }
+    }
+
+    /// SIGKILLs every process the sandbox left behind, bwrap included, and
+    /// reaps it. As a subreaper we are an ancestor of all of them, even of
+    /// those that daemonized.
+    fn kill_tree(&mut self) -> ExitStatus {
+        let killed = linux::kill_tree(std::process::id(), false, Duration::from_secs(2));
+        if killed > 0 {
+            debug_log!("killed {} leftover sandbox processes", killed);
+        }
+        let status = match self.child.try_wait() {
+            Ok(Some(status)) => status,
+            _ => ExitStatus::from_raw(libc::SIGKILL),
+        };
+        linux::reap_children();
+        status
+    }
+
+    /// Sleeps for `delay`, still handling signals.
+    fn sleep(&mut self, delay: Duration) {
+        let deadline = Instant::now() + delay;
+        while Instant::now() < deadline {
+            self.handle_signals();
+            thread::sleep(
+                deadline
+                    .saturating_duration_since(Instant::now())
+                    .min(Duration::from_millis(100)),
+            );
+        }
+    }
+
+    /// Forwards `signal` to the sandbox and exits with the conventional
+    /// 128+signal code.
+    fn shut_down(&mut self, signal: i32) -> ! {
+        info_log!("received signal {}, forwarding to sandbox", signal);
+        self.stop(signal, 128 + signal);
+    }
+
+    /// Sends `signal` to the sandbox, gives it `shutdown_timeout` to exit,
+    /// then kills it. Exits with `code`.
+    fn stop(&mut self, signal: i32, code: i32) -> ! {
+        linux::signal_sandbox(self.child.id(), signal);
+
+        let deadline = Instant::now() + self.shutdown_timeout;
+        while Instant::now() < deadline {
+            if let Ok(Some(status)) = self.child.try_wait() {
+                debug_log!(
+                    "sandbox exited after signal {} with status: {}",
+                    signal,
+                    status
+                );
+                self.exit(code);
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        eprintln!(
+            "error: sandbox still running {}s after signal {}, killing it",
+            self.shutdown_timeout.as_secs(),
+            signal
+        );
+        self.exit(code);
+    }
+
+    /// Stops the app and exits cleanly once it had no connections for
+    /// `idle_timeout`, so that Smallweb starts it afresh on the next request.
+    fn check_idle(&mut self) {
+        let Some(idle_timeout) = self.idle_timeout else {
+            return;
+        };
+        // The app accepts on the passed listener itself, so only the kernel
+        // knows about its connections.
+        if let Inbound::Activated(_) = self.listen.inbound {
+            if linux::has_connections(self.listen.port) {
+                self.activity.touch();
+            }
+        }
+        if self.activity.idle_for() >= idle_timeout {
+            let idle_secs = idle_timeout.as_secs();
+            logging::event(Level::Info, Event::IdleShutdown { idle_secs });
+            self.stop(libc::SIGTERM, 0);
+        }
+    }
+
+    fn handle_signals(&mut self) {
+        if let Some(signal) = linux::take_pending_signal() {
+            self.shut_down(signal);
+        }
+    }
+}
+
+/// Spawns the sandbox, feeds it `shell_script`, prints READY once the port is
+/// listening and then supervises it until it exits, restarting it as the
+/// app's restart policy allows. With a Unix socket inbound, the socket is
+/// waited for instead and the port bridged to it; a remapped app is waited
+/// for on its own port in the sandbox and bridged through the forwarder;
+/// with an activated listener, READY is printed as soon as the sandbox is
+/// spawned. A notify socket overrides all of these. Gives up when the app
+/// isn't ready within `startup_timeout`. Never returns.
+pub fn spawn_and_wait_for_port(
+    command: &mut Command,
+    listen: Listen,
+    startup_timeout: Duration,
+    shell_script: Option<&str>,
+    config: &SmallwebConfig,
+    app_dir: &Path,
+    invocation: Invocation,
+) -> ! {
+    let port = listen.port;
+    // Signals arriving from now on are forwarded to the sandbox rather than
+    // killing us, and with it the sandbox via --die-with-parent.
+    linux::install_signal_handlers();
+    // Daemonizing app processes get reparented to us, so nothing in the
+    // sandbox can outlive the adapter.
+    linux::become_subreaper();
+    // Keep bwrap out of our process group so a terminal's Ctrl-C reaches the
+    // app only through our forwarding.
+    command.process_group(0);
+    if shell_script.is_some() {
+        command.stdin(Stdio::piped());
+    }
+    // Output is always passed through us, to keep the tail of stderr for
+    // the exit diagnostics.
+    command.stdout(Stdio::piped());
+    command.stderr(Stdio::piped());
+    if let Inbound::Activated(listener) = &listen.inbound {
+        linux::pass_listen_fd(command, listener.as_raw_fd());
+    }
+
+    let app_log = match RotatingLog::for_app(config.log.as_ref(), app_dir) {
+        Ok(log) => Some(Arc::new(Mutex::new(log))),
+        Err(e) => {
+            debug_log!("app log capture disabled: {}", e);
+            None
+        }
+    };
+
+    let child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            error_log!("Failed to spawn child process: {}", e);
+            eprintln!("Failed to spawn child process: {}", e);
+            listen.remove_dirs();
+            invocation.finish(Some(1));
+            std::process::exit(1);
+        }
+    };
+    logging::event(
+        Level::Info,
+        Event::SandboxSpawned {
+            child_pid: child.id(),
+        },
+    );
+    let mut sandbox = Sandbox {
+        child,
+        capture: None,
+        app_log,
+        startup_timeout,
+        shutdown_timeout: Duration::from_secs(config.shutdown_timeout.unwrap_or(10)),
+        idle_timeout: config.idle_timeout.map(Duration::from_secs),
+        activity: Arc::new(Activity::new()),
+        invocation: Some(invocation),
+        stderr_tail: Vec::new(),
+        oom_kills: None,
+        listen,
+    };
+    if let Err(e) = sandbox.attach(shell_script) {
+        eprintln!("error: failed to write to child stdin: {}", e);
+        sandbox.exit(1);
+    }
+
+    match sandbox.wait_for_port() {
+        Ok(elapsed) => {
+            if let Some(socket_dir) = sandbox.listen.inbound.socket_dir() {
+                let upstream = Upstream::Unix(socket_dir.host_socket());
+                match proxy::bind(port) {
+                    Ok(listener) => {
+                        proxy::spawn_bridge(listener, upstream, Arc::clone(&sandbox.activity))
+                    }
+                    Err(e) => {
+                        error_log!("failed to bind port {}: {}", port, e);
+                        eprintln!("error: failed to bind port {}: {}", port, e);
+                        sandbox.exit(1);
+                    }
+                }
+            }
+            let startup_ms = elapsed.as_millis() as u64;
+            logging::event(Level::Info, Event::Ready { port, startup_ms });
+            eprintln!("READY");
+            // The idle timer starts now, not when the app was spawned.
+            sandbox.activity.touch();
+        }
+        Err(StartupFailure::Exited(status)) => {
+            let reason = sandbox.report_exit(status, true);
+            sandbox.exit(reason.exit_code());
+        }
+        Err(StartupFailure::TimedOut) => {
+            let timeout_secs = sandbox.startup_timeout.as_secs();
+            logging::event(Level::Error, Event::Timeout { port, timeout_secs });
+            eprintln!("error: timed out waiting for port {}", port);
+            sandbox.exit(1);
+        }
+    }
+
+    let policy = config.restart.clone().unwrap_or(RestartPolicy {
+        mode: Some(RestartMode::Never),
+        max_retries: None,
+        backoff_ms: None,
+        max_backoff_ms: None,
+    });
+    let mut attempt = 0;
+    let mut up_since = Instant::now();
+    let mut status = sandbox.wait();
+    let mut reason = sandbox.report_exit(status, false);
+    loop {
+        if up_since.elapsed() >= STABLE_AFTER {
+            attempt = 0;
+        }
+        if !policy.should_restart(status) || attempt >= policy.max_retries() {
+            sandbox.exit(reason.exit_code());
+        }
+        attempt += 1;
+        let delay = policy.delay(attempt);
+        logging::event(
+            Level::Warn,
+            Event::Restart {
+                attempt,
+                delay_ms: delay.as_millis() as u64,
+            },
+        );
+        // Workers the crashed app left behind could still hold the port.
+        sandbox.kill_tree();
+        sandbox.sleep(delay);
+
+        if let Err(e) = sandbox.respawn(command, shell_script) {
+            error_log!("failed to restart app: {}", e);
+            eprintln!("error: failed to restart app: {}", e);
+            sandbox.exit(1);
+        }
+        up_since = Instant::now();
+        // Smallweb already saw READY; a restart only has to come back up.
+        let early = match sandbox.wait_for_port() {
+            Ok(elapsed) => {
+                let startup_ms = elapsed.as_millis() as u64;
+                logging::event(Level::Info, Event::Ready { port, startup_ms });
+                status = sandbox.wait();
+                false
+            }
+            Err(StartupFailure::Exited(exited)) => {
+                status = exited;
+                true
+            }
+            Err(StartupFailure::TimedOut) => {
+                let timeout_secs = sandbox.startup_timeout.as_secs();
+                logging::event(Level::Error, Event::Timeout { port, timeout_secs });
+                status = sandbox.kill_tree();
+                true
+            }
+        };
+        reason = sandbox.report_exit(status, early);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+    use crate::history::HistorySettings;
+    use tempfile::tempdir;
+
+    /// Set in the copy of the test binary that plays the adapter, to the
+    /// app's shell script.
+    const APP_SCRIPT_ENV: &str = "SMALLWEB_ADAPTER_TEST_APP_SCRIPT";
+
+    /// Runs `test` again in a process of its own, which launches `script`
+    /// through `spawn_and_wait_for_port` and so becomes the subreaper of
+    /// everything the script forks. `$PIDS` names a file the script records
+    /// its processes in. Returns the exit code and the recorded pids.
+    fn run_adapter(test: &str, script: &str) -> (Option<i32>, Vec<u32>) {
+        let temp_dir = tempdir().unwrap();
+        let pids = temp_dir.path().join("pids");
+        let status = Command::new(env::current_exe().unwrap())
+            .args([test, "--exact", "--nocapture"])
+            .env(APP_SCRIPT_ENV, script)
+            .env("PIDS", &pids)
+            .current_dir(temp_dir.path())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        let content = fs::read_to_string(&pids).unwrap_or_default();
+        (
+            status.code(),
+            content
+                .lines()
+                .filter_map(|l| l.trim().parse().ok())
+                .collect(),
+        )
+    }
+
+    /// The adapter's side of `run_adapter`, when this process is the copy.
+    fn play_adapter() {
+        let Ok(script) = env::var(APP_SCRIPT_ENV) else {
+            return;
+        };
+        // Nothing listens on a port we just bound and released.
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config = parse_config(r#"{"exec":"./app"}"#).unwrap().unwrap();
+        let history = HistorySettings {
+            path: None,
+            enabled: Some(false),
+        };
+        let invocation = Invocation::start(&history, None, None, "exec", &[]);
+        let listen = Listen {
+            port,
+            inbound: Inbound::Port,
+            notify: None,
+        };
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        spawn_and_wait_for_port(
+            &mut command,
+            listen,
+            Duration::from_secs(1),
+            None,
+            &config,
+            Path::new("."),
+            invocation,
+        )
+    }
+
+    /// Forks a worker and a daemon in a session of its own, as servers that
+    /// daemonize do, and records both.
+    const FORK_WORKERS: &str = "sleep 300 & echo $! >> \"$PIDS\"
+        (setsid sh -c 'sleep 300 & echo $! >> \"$PIDS\"; wait' &)
+        while [ $(wc -l < \"$PIDS\") -lt 2 ]; do sleep 0.05; done";
+
+    #[test]
+    fn test_early_exit_kills_daemons() {
+        play_adapter();
+        let (code, pids) = run_adapter(
+            "supervisor::tests::test_early_exit_kills_daemons",
+            &format!("{}\nexit 3", FORK_WORKERS),
+        );
+        assert_eq!(code, Some(3));
+        assert_eq!(pids.len(), 2);
+        for pid in pids {
+            assert!(!linux::is_alive(pid), "process {} survived", pid);
+        }
+    }
+
+    #[test]
+    fn test_startup_timeout_kills_daemons() {
+        play_adapter();
+        let start = Instant::now();
+        let (code, pids) = run_adapter(
+            "supervisor::tests::test_startup_timeout_kills_daemons",
+            &format!("{}\nexec sleep 300", FORK_WORKERS),
+        );
+        assert_eq!(code, Some(1));
+        assert!(start.elapsed() < Duration::from_secs(30));
+        assert_eq!(pids.len(), 2);
+        for pid in pids {
+            assert!(!linux::is_alive(pid), "process {} survived", pid);
+        }
+    }
+
+    fn policy(mode: RestartMode) -> RestartPolicy {
+        RestartPolicy {
+            mode: Some(mode),
+            max_retries: None,
+            backoff_ms: Some(500),
+            max_backoff_ms: Some(3000),
+        }
+    }
+
+    #[test]
+    fn test_restart_decision() {
+        let ok = ExitStatus::from_raw(0);
+        let failed = ExitStatus::from_raw(1 << 8);
+        let killed = ExitStatus::from_raw(libc::SIGKILL);
+
+        assert!(!policy(RestartMode::Never).should_restart(failed));
+        assert!(!policy(RestartMode::OnFailure).should_restart(ok));
+        assert!(policy(RestartMode::OnFailure).should_restart(failed));
+        assert!(policy(RestartMode::OnFailure).should_restart(killed));
+        assert!(policy(RestartMode::Always).should_restart(ok));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = policy(RestartMode::Always);
+        let delays: Vec<u64> = (1..=5)
+            .map(|n| policy.delay(n).as_millis() as u64)
+            .collect();
+        assert_eq!(delays, [500, 1000, 2000, 3000, 3000]);
+        assert_eq!(policy.delay(200), Duration::from_millis(3000));
+        assert_eq!(policy.max_retries(), 5);
+    }
+
+    #[test]
+    fn test_private_dirs_are_unpredictable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (a, b) = (private_dir("-test").unwrap(), private_dir("-test").unwrap());
+        assert_ne!(a, b);
+        for dir in [a, b] {
+            let name = dir.file_name().unwrap().to_string_lossy().into_owned();
+            let prefix = format!("smallweb-adapter-{}-test-", std::process::id());
+            assert!(
+                name.starts_with(&prefix) && name.len() == prefix.len() + 6,
+                "{}",
+                name
+            );
+            assert_eq!(
+                fs::metadata(&dir).unwrap().permissions().mode() & 0o777,
+                0o700
+            );
+            remove_dir(&dir);
+        }
+    }
+
+    #[test]
+    fn test_notify_messages() {
+        assert!(!handle_notify_message("STATUS=loading model\n"));
+        assert!(handle_notify_message("STATUS=serving\nREADY=1\nMAINPID=42"));
+        assert!(!handle_notify_message("READY=0"));
+
+        let notify = NotifySocket::create().unwrap();
+        let client = UnixDatagram::unbound().unwrap();
+        assert!(!notify.poll());
+        client.send_to(b"STATUS=starting", notify.path()).unwrap();
+        client.send_to(b"READY=1", notify.path()).unwrap();
+        assert!(notify.poll());
+        assert!(!notify.poll());
+        notify.remove();
+        assert!(!notify.path().exists());
+    }
+}